@@ -0,0 +1,138 @@
+use image::{DynamicImage, Rgba};
+use show_image::glam::{UVec2, Vec2};
+
+/// What is shown behind transparent parts of images.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackgroundMode {
+    Checkerboard,
+    /// The configured solid color
+    Solid,
+    White,
+    Black,
+}
+
+impl BackgroundMode {
+    /// The mode after this one, to cycle through them.
+    pub fn next(&self) -> BackgroundMode {
+        match self {
+            BackgroundMode::Checkerboard => BackgroundMode::Solid,
+            BackgroundMode::Solid => BackgroundMode::White,
+            BackgroundMode::White => BackgroundMode::Black,
+            BackgroundMode::Black => BackgroundMode::Checkerboard,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BackgroundMode::Checkerboard => "checkerboard",
+            BackgroundMode::Solid => "solid",
+            BackgroundMode::White => "white",
+            BackgroundMode::Black => "black",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Background {
+    pub mode: BackgroundMode,
+    /// Side of the checkerboard squares, in window pixels when the image
+    /// fits in the window.
+    pub checker_size: u32,
+    pub checker_colors: [Rgba<u8>; 2],
+    pub solid: Rgba<u8>,
+}
+
+impl Default for Background {
+    fn default() -> Self {
+        Background {
+            mode: BackgroundMode::Checkerboard,
+            checker_size: 16,
+            checker_colors: [Rgba([153, 153, 153, 255]), Rgba([102, 102, 102, 255])],
+            solid: Rgba([48, 48, 48, 255]),
+        }
+    }
+}
+
+impl Background {
+    /// Color of the window around the image, and behind it unless it gets a
+    /// checkerboard.
+    pub fn color(&self) -> Rgba<u8> {
+        match self.mode {
+            BackgroundMode::Checkerboard | BackgroundMode::Black => Rgba([0, 0, 0, 255]),
+            BackgroundMode::Solid => self.solid,
+            BackgroundMode::White => Rgba([255, 255, 255, 255]),
+        }
+    }
+
+    /// The image ready to be put in a window of size `window`: over a
+    /// checkerboard if it has transparency and that's the mode. Solid colors
+    /// are the window's own.
+    pub fn apply(&self, image: DynamicImage, window: Vec2) -> DynamicImage {
+        let size = Vec2::new(image.width() as f32, image.height() as f32);
+        self.apply_part(image, size, UVec2::ZERO, window)
+    }
+
+    /// Like `apply`, for the part at `origin` of an image of size `whole`,
+    /// so that the squares line up with those of the whole image.
+    pub fn apply_part(
+        &self,
+        image: DynamicImage,
+        whole: Vec2,
+        origin: UVec2,
+        window: Vec2,
+    ) -> DynamicImage {
+        if self.mode != BackgroundMode::Checkerboard || !image.color().has_alpha() {
+            return image;
+        }
+
+        // Squares sized in image pixels, to look `checker_size` once fit
+        let fit = (window / whole).min_element();
+        let check = if fit.is_finite() && fit > 0.0 {
+            (self.checker_size as f32 / fit).round().max(1.0) as u32
+        } else {
+            self.checker_size
+        };
+
+        let mut image = image.to_rgba8();
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            let square = (x + origin.x) / check + (y + origin.y) / check;
+            let mut under = self.checker_colors[(square % 2) as usize];
+            let a = pixel[3] as u32;
+            for i in 0..3 {
+                under[i] = ((pixel[i] as u32 * a + under[i] as u32 * (255 - a)) / 255) as u8;
+            }
+            *pixel = under;
+        }
+        DynamicImage::ImageRgba8(image)
+    }
+}
+
+/// Parse `#RRGGBB` (the `#` is optional), or one of a few names.
+pub fn parse_color(s: &str) -> Option<Rgba<u8>> {
+    match s {
+        "white" => return Some(Rgba([255, 255, 255, 255])),
+        "black" => return Some(Rgba([0, 0, 0, 255])),
+        "gray" | "grey" => return Some(Rgba([128, 128, 128, 255])),
+        _ => {}
+    }
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some(Rgba([channel(0)?, channel(2)?, channel(4)?, 255]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_color() {
+        assert_eq!(Some(Rgba([255, 0, 128, 255])), parse_color("#ff0080"));
+        assert_eq!(Some(Rgba([16, 32, 48, 255])), parse_color("102030"));
+        assert_eq!(Some(Rgba([255, 255, 255, 255])), parse_color("white"));
+        assert_eq!(None, parse_color("#fff"));
+        assert_eq!(None, parse_color("#gg0000"));
+    }
+}
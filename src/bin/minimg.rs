@@ -1,10 +1,35 @@
 use anyhow::{anyhow, Result};
-use log::debug;
-use minimg::fs_utils::{start_file_reader, Direction, FileStatus, ImagePair};
-use minimg::window::{generate_window, Rotation};
+use log::{debug, error};
+use minimg::background::{parse_color, Background, BackgroundMode};
+use minimg::diff::DiffOptions;
+use minimg::filmstrip::Filmstrip;
+use minimg::fs_utils::{
+    is_supported_image, load_subimage, open_image, open_subimage, start_file_reader, DecodeLimits,
+    Direction, FileStatus, ImageDetails, ImagePair, LoadOptions,
+};
+use minimg::gallery::{Gallery, GalleryMove};
+use minimg::pairs::Pairing;
+use minimg::view::{FitMode, Flip, Rotation, ViewMemory, ViewPolicy, ViewState};
+use minimg::window::{generate_window, Window};
+use minimg::{hud, sandbox, straighten, svg};
 use show_image::event;
-use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use threadpool::ThreadPool;
+
+/// Zoom factor of a mouse wheel notch.
+const WHEEL_ZOOM: f32 = 1.1;
+/// Images kept decoded on each side of the current one.
+const CACHE_SIDE: usize = 5;
+/// Files decoded at once, by the readers and the gallery together.
+const DECODE_THREADS: usize = 4;
+/// How close to the wipe line (window pixels) a drag moves it.
+const WIPE_GRAB: f32 = 8.0;
+/// How often to check for a page being decoded.
+const PAGE_POLL: Duration = Duration::from_millis(15);
 
 fn get_next_idx(idx: usize, len: usize, d: Direction) -> usize {
     match d {
@@ -20,25 +45,38 @@ fn get_next_idx(idx: usize, len: usize, d: Direction) -> usize {
     }
 }
 
+fn get_next_subimage(details: &ImageDetails, d: Direction) -> usize {
+    get_next_idx(details.subimage, details.subimages.max(1), d)
+}
+
+/// An image read and its index, or the index of one that could not be.
+type ReadResult = Result<(usize, ImagePair), (usize, String)>;
+
+/// Check whether the reader thread has an image (or the reason why it could
+/// not load one) ready for display.
 fn try_get_image(
-    rx: &Receiver<Result<Option<usize>, String>>,
+    rx: &Receiver<Result<Option<usize>, (usize, String)>>,
     cache: Arc<RwLock<Vec<FileStatus<ImagePair>>>>,
-) -> Result<Option<ImagePair>> {
+) -> Result<Option<ReadResult>> {
     match rx.try_recv() {
         Ok(maybe_img) => {
             debug!("Received next image_pair idx {:?}", maybe_img);
 
-            if let Ok(Some(idx)) = maybe_img {
-                debug!("Load image_pair from cache at idx {:?}", idx);
+            match maybe_img {
+                Ok(Some(idx)) => {
+                    debug!("Load image_pair from cache at idx {:?}", idx);
 
-                match cache.read().unwrap().get(idx) {
-                    Some(FileStatus::Read(image_pair))
-                    | Some(FileStatus::Thumbnail(image_pair)) => {
-                        debug!("Got imagPair");
-                        return Ok(Some(image_pair.clone()));
+                    match cache.read().unwrap().get(idx) {
+                        Some(FileStatus::Read(image_pair))
+                        | Some(FileStatus::Thumbnail(image_pair)) => {
+                            debug!("Got imagPair");
+                            return Ok(Some(Ok((idx, image_pair.clone()))));
+                        }
+                        _ => {}
                     }
-                    _ => {}
                 }
+                Ok(None) => {}
+                Err(e) => return Ok(Some(Err(e))),
             }
         }
         Err(TryRecvError::Empty) => {
@@ -54,18 +92,850 @@ fn try_get_image(
     Ok(None)
 }
 
-#[show_image::main]
-fn main() -> Result<()> {
-    env_logger::Builder::from_default_env()
-        .format_timestamp_millis()
-        .init();
+enum GalleryAction {
+    Stay,
+    /// Back to single images, at this one
+    Open(usize),
+    Quit,
+}
 
-    let args: Vec<_> = std::env::args().collect();
-    if args.len() < 2 || args.iter().any(|a| a == "-h" || a == "--help") {
-        let bin = &args[0];
-        eprintln!(
-            "\
-Usage: {bin} [IMAGE or DIR]...
+/// Browse the gallery with `event`.
+fn handle_gallery_event(gallery: &mut Gallery, event: &event::WindowEvent) -> GalleryAction {
+    match event {
+        event::WindowEvent::Resized(event) => gallery.resize(event.size),
+        event::WindowEvent::MouseWheel(event) => {
+            let rows = match event.delta {
+                event::MouseScrollDelta::LineDelta(_, y) => -y,
+                event::MouseScrollDelta::PixelDelta(delta) => -delta.y as f32 / 100.0,
+            };
+            gallery.scroll(rows);
+        }
+        // Clicks select, double clicks open
+        event::WindowEvent::MouseButton(event)
+            if event.button == event::MouseButton::Left
+                && event.state.is_pressed()
+                && gallery.click(event.position) =>
+        {
+            return GalleryAction::Open(gallery.close());
+        }
+        event::WindowEvent::KeyboardInput(event)
+            if !event.is_synthetic && event.input.state.is_pressed() =>
+        {
+            use event::{Key, NamedKey};
+            let direction = match &event.input.logical_key {
+                Key::Named(NamedKey::ArrowLeft) => GalleryMove::Left,
+                Key::Named(NamedKey::ArrowRight) => GalleryMove::Right,
+                Key::Named(NamedKey::ArrowUp) => GalleryMove::Up,
+                Key::Named(NamedKey::ArrowDown) => GalleryMove::Down,
+                Key::Named(NamedKey::PageUp) => GalleryMove::PageUp,
+                Key::Named(NamedKey::PageDown) => GalleryMove::PageDown,
+                Key::Named(NamedKey::Home) => GalleryMove::First,
+                Key::Named(NamedKey::End) => GalleryMove::Last,
+                Key::Named(NamedKey::Enter) => return GalleryAction::Open(gallery.close()),
+                // Back to where the gallery was opened from
+                Key::Named(NamedKey::Escape) => return GalleryAction::Open(gallery.cancel()),
+                Key::Character(c) if c == "t" => return GalleryAction::Open(gallery.cancel()),
+                Key::Character(c) if c == "q" => return GalleryAction::Quit,
+                _ => return GalleryAction::Stay,
+            };
+            gallery.move_selection(direction);
+        }
+        _ => {}
+    }
+    GalleryAction::Stay
+}
+
+/// Render vector images again at the resolution they're currently displayed
+/// at, so that they stay crisp while zooming.
+fn refresh_vector_image(window: &Window, path: &Path, options: &LoadOptions) {
+    if !svg::is_svg(path) {
+        return;
+    }
+
+    let Some(current) = window.image_size() else {
+        return;
+    };
+    let Some(target) = window.displayed_size() else {
+        return;
+    };
+    let target = (target.x.round() as u32, target.y.round() as u32);
+
+    if svg::needs_rerasterize((current.x, current.y), target) {
+        debug!("Rasterize {:?} again at {:?}", path, target);
+        let options = LoadOptions {
+            svg_size: target,
+            ..options.clone()
+        };
+        let res =
+            open_image(path, &options)
+                .map_err(|e| anyhow!(e))
+                .and_then(|(image, details)| {
+                    window.replace_image(ImagePair(path.to_owned(), Some(image), details))
+                });
+        if let Err(e) = res {
+            error!("Cannot rasterize {:?} again: {}", path, e);
+        }
+    }
+}
+
+/// Show a new image, the `index`th of `total`, with the view `memory` has
+/// for it or else sized as `fit_mode`.
+fn display(
+    window: &Window,
+    image_pair: ImagePair,
+    (index, total): (usize, usize),
+    fit_mode: Option<FitMode>,
+    memory: &mut ViewMemory,
+) -> Result<()> {
+    if window.comparing() {
+        // The view stays the one of the image kept
+        return window.set_image(image_pair);
+    }
+    if let Some(size) = window.image_size() {
+        memory.leave(window.view(), size.as_vec2());
+    }
+    let path = image_pair.0.clone();
+    let details = image_pair.2.clone();
+    window.set_image(image_pair)?;
+    window.set_info(hud::describe(&path, index, total, Some(&details)))?;
+
+    let size = window.image_size();
+    match size.and_then(|size| memory.enter(&path, details.subimage, size.as_vec2())) {
+        Some(view) => window.restore_view(view)?,
+        None => {
+            if let Some(mode) = fit_mode {
+                window.apply_fit(mode);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write the image as `view` orients it next to `path`, cropped so that a
+/// tilt leaves no empty corner. The file is decoded again at full size.
+fn save_straightened(
+    path: &Path,
+    subimage: usize,
+    view: &ViewState,
+    options: &LoadOptions,
+) -> Result<PathBuf> {
+    let (image, _) = open_subimage(path, subimage, options).map_err(|e| anyhow!(e))?;
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let target = path.with_file_name(format!("{}-straightened.png", stem));
+    straighten::straighten(&image, view).save(&target)?;
+    Ok(target)
+}
+
+/// Commands taking a value typed in the title bar.
+#[derive(Clone, Copy)]
+enum Prompt {
+    Zoom,
+    Angle,
+}
+
+impl Prompt {
+    fn show(&self, entry: &str) -> String {
+        match self {
+            Prompt::Zoom => format!("Zoom to {}%", entry),
+            Prompt::Angle => format!("Rotate to {}°", entry),
+        }
+    }
+}
+
+fn parse_angle(s: &str) -> Result<f32> {
+    s.parse()
+        .ok()
+        .filter(|a: &f32| a.is_finite())
+        .ok_or_else(|| anyhow!("Invalid angle {:?}", s))
+}
+
+enum ViewerAction {
+    Stay,
+    Quit,
+}
+
+/// Browsing single images, or comparing them, as the input has it.
+struct Viewer {
+    /// Image to show, of `num_images`
+    idx: usize,
+    num_images: usize,
+    /// Whether to request idx again, to show it after the gallery
+    reload: bool,
+    /// Whether the images come in pairs, see --pair
+    paired: bool,
+    current_path: PathBuf,
+    current_details: ImageDetails,
+    load_options: LoadOptions,
+    /// Last known mouse position, zoom gestures are anchored to it
+    cursor: Option<show_image::glam::Vec2>,
+    /// Sizing kept across images and window resizes, until the user zooms
+    fit_mode: Option<FitMode>,
+    /// Views of the images seen, for the next ones
+    view_memory: ViewMemory,
+    /// Whether the wipe line is being dragged
+    wiping: bool,
+    /// Image kept for comparing while only its preview was decoded, to keep
+    /// the full one once it is
+    kept_preview: Option<usize>,
+    filmstrip: Filmstrip,
+    /// Whether the strip of neighboring images is shown
+    show_filmstrip: bool,
+    /// Whether the pixel under the cursor is described as it moves
+    inspector: bool,
+    /// Value being typed after `z` or `a`
+    entry: Option<(Prompt, String)>,
+    /// Other pages of the current file are decoded here, off the event loop
+    page_pool: ThreadPool,
+    tx_page: Sender<(usize, usize, Result<ImagePair>)>,
+    /// Image and page being decoded, see `page_pool`
+    loading_page: Option<(usize, usize)>,
+}
+
+impl Viewer {
+    /// Browse with `event`.
+    fn handle_event(
+        &mut self,
+        window: &Window,
+        gallery: &mut Gallery,
+        event: &event::WindowEvent,
+    ) -> Result<ViewerAction> {
+        let event::WindowEvent::KeyboardInput(event) = event else {
+            self.handle_pointer_event(window, event)?;
+            return Ok(ViewerAction::Stay);
+        };
+        if event.is_synthetic || !event.input.state.is_pressed() {
+            return Ok(ViewerAction::Stay);
+        }
+        let key = &event.input.logical_key;
+        debug!("Keyboard event {:?}", key);
+
+        if self.entry.is_some() {
+            self.handle_entry_key(window, key)?;
+        } else if !self
+            .handle_navigation_key(key, event.modifiers.contains(event::ModifiersState::SHIFT))
+            && !self.handle_view_key(window, key)?
+            && !self.handle_compare_key(window, key)?
+        {
+            return self.handle_other_key(window, gallery, key);
+        }
+        Ok(ViewerAction::Stay)
+    }
+
+    /// Pan, zoom, pick from the filmstrip or move the wipe line with the
+    /// mouse, or follow the window size.
+    fn handle_pointer_event(&mut self, window: &Window, event: &event::WindowEvent) -> Result<()> {
+        match event {
+            event::WindowEvent::MouseButton(event)
+                if event.button == event::MouseButton::Left && event.state.is_pressed() =>
+            {
+                let point = window.filmstrip_point(event.position);
+                if let Some(index) = point.and_then(|point| self.filmstrip.index_at(point)) {
+                    self.idx = index;
+                }
+                self.wiping = window
+                    .wipe_line()
+                    .is_some_and(|line| (event.position.x - line).abs() <= WIPE_GRAB);
+            }
+            event::WindowEvent::MouseButton(event) if event.button == event::MouseButton::Left => {
+                self.wiping = false;
+            }
+            event::WindowEvent::MouseMove(event) => {
+                self.cursor = Some(event.position);
+                if self.wiping {
+                    window.set_wipe(event.position.x);
+                } else if event.buttons.is_pressed(event::MouseButton::Left) {
+                    let delta = (event.position - event.prev_position) / window.viewport_size();
+                    window.pan(delta.x, delta.y);
+                }
+                if self.inspector {
+                    window.show_inspection(window.inspect(event.position))?;
+                }
+            }
+            event::WindowEvent::MouseLeave(_) => self.cursor = None,
+            event::WindowEvent::MouseWheel(event) => {
+                let notches = match event.delta {
+                    event::MouseScrollDelta::LineDelta(_, y) => y,
+                    event::MouseScrollDelta::PixelDelta(delta) => delta.y as f32 / 100.0,
+                };
+                window.zoom_by(WHEEL_ZOOM.powf(notches), event.position.or(self.cursor));
+                self.fit_mode = None;
+            }
+            event::WindowEvent::TouchpadMagnify(event) => {
+                window.zoom_by((1.0 + event.delta as f32).max(0.1), self.cursor);
+                self.fit_mode = None;
+            }
+            event::WindowEvent::Resized(_) => match self.fit_mode {
+                Some(mode) => window.apply_fit(mode),
+                None => window.refresh_view(),
+            },
+            _ => return Ok(()),
+        }
+
+        if matches!(
+            event,
+            event::WindowEvent::MouseWheel(_)
+                | event::WindowEvent::TouchpadMagnify(_)
+                | event::WindowEvent::Resized(_)
+        ) {
+            refresh_vector_image(window, &self.current_path, &self.load_options);
+        }
+        Ok(())
+    }
+
+    /// Type the value asked for in the title bar, then apply it.
+    fn handle_entry_key(&mut self, window: &Window, key: &event::Key) -> Result<()> {
+        use event::{Key, NamedKey};
+        let Some((prompt, text)) = self.entry.as_mut() else {
+            return Ok(());
+        };
+        match key {
+            Key::Character(c)
+                if c.chars()
+                    .all(|c| c.is_ascii_digit() || c == '.' || c == '-') =>
+            {
+                text.push_str(c)
+            }
+            Key::Named(NamedKey::Backspace) => {
+                text.pop();
+            }
+            Key::Named(NamedKey::Enter) => {
+                match prompt {
+                    Prompt::Zoom => match parse_percent(text) {
+                        Ok(percent) => {
+                            window.zoom_to(percent);
+                            self.fit_mode = Some(FitMode::Percent(percent));
+                            refresh_vector_image(window, &self.current_path, &self.load_options);
+                        }
+                        Err(e) => error!("{}", e),
+                    },
+                    Prompt::Angle => match parse_angle(text) {
+                        Ok(degrees) => {
+                            window.set_rotation(degrees)?;
+                            self.apply_fit(window);
+                        }
+                        Err(e) => error!("{}", e),
+                    },
+                }
+                self.entry = None;
+            }
+            // Escape, or anything else, cancels
+            _ => self.entry = None,
+        }
+        let prompt = self.entry.as_ref().map(|(prompt, text)| prompt.show(text));
+        window.show_prompt(prompt.as_deref())
+    }
+
+    /// Go to another image or page, if `key` (with `shift` or not) is for
+    /// that.
+    fn handle_navigation_key(&mut self, key: &event::Key, shift: bool) -> bool {
+        use event::{Key, NamedKey};
+        let direction = match key {
+            Key::Character(c) if c == " " && shift => Direction::Left,
+            Key::Character(c) if c == " " || c == "l" || c == "n" => Direction::Right,
+            Key::Character(c) if c == "h" || c == "p" || c == "N" => Direction::Left,
+            Key::Named(NamedKey::Backspace) => Direction::Left,
+            Key::Named(NamedKey::Home) => Direction::First,
+            Key::Named(NamedKey::End) => Direction::Last,
+            Key::Named(NamedKey::PageDown) => {
+                self.turn_page(Direction::Right);
+                return true;
+            }
+            Key::Named(NamedKey::PageUp) => {
+                self.turn_page(Direction::Left);
+                return true;
+            }
+            _ => return false,
+        };
+        self.idx = get_next_idx(self.idx, self.num_images, direction);
+        true
+    }
+
+    /// Decode the next page of the current file in `direction`, if any.
+    fn turn_page(&mut self, direction: Direction) {
+        // From the page still loading, if any
+        let shown = match self.loading_page {
+            Some((page_idx, page)) if page_idx == self.idx => page,
+            _ => self.current_details.subimage,
+        };
+        let details = ImageDetails {
+            subimage: shown,
+            ..self.current_details.clone()
+        };
+        let subimage = get_next_subimage(&details, direction);
+        if subimage == shown {
+            return;
+        }
+        debug!("Show subimage {} of {:?}", subimage, self.current_path);
+        self.loading_page = Some((self.idx, subimage));
+        let tx = self.tx_page.clone();
+        let path = self.current_path.clone();
+        let options = self.load_options.clone();
+        let page_idx = self.idx;
+        self.page_pool.execute(move || {
+            let result = load_subimage(&path, subimage, &options).map_err(|e| anyhow!(e));
+            let _ = tx.send((page_idx, subimage, result));
+        });
+    }
+
+    /// Pan, zoom, orient the image or change how it looks, if `key` is for
+    /// that.
+    fn handle_view_key(&mut self, window: &Window, key: &event::Key) -> Result<bool> {
+        use event::{Key, NamedKey};
+        match key {
+            Key::Named(NamedKey::ArrowUp) => window.pan(0.0, 0.05),
+            Key::Named(NamedKey::ArrowDown) => window.pan(0.0, -0.05),
+            Key::Named(NamedKey::ArrowRight) => window.pan(-0.05, 0.0),
+            Key::Named(NamedKey::ArrowLeft) => window.pan(0.05, 0.0),
+            Key::Character(c) if c == "0" => {
+                window.reset_image();
+                self.fit_mode = Some(FitMode::Fit);
+                refresh_vector_image(window, &self.current_path, &self.load_options);
+            }
+            Key::Character(c) if c == "-" || c == "=" => {
+                if c == "-" {
+                    window.scale_down();
+                } else {
+                    window.scale_up();
+                }
+                self.fit_mode = None;
+                refresh_vector_image(window, &self.current_path, &self.load_options);
+            }
+            Key::Character(c) if c == "z" || c == "a" => {
+                let prompt = if c == "z" {
+                    Prompt::Zoom
+                } else {
+                    Prompt::Angle
+                };
+                window.show_prompt(Some(&prompt.show("")))?;
+                self.entry = Some((prompt, String::new()));
+            }
+            Key::Character(c) if matches!(c.as_str(), "w" | "W" | "1" | "e" | "E") => {
+                let mode = match c.as_str() {
+                    "W" => FitMode::Fill,
+                    "1" => FitMode::Actual,
+                    "e" => FitMode::FitWidth,
+                    "E" => FitMode::FitHeight,
+                    _ => FitMode::Fit,
+                };
+                self.fit_mode = Some(mode);
+                window.apply_fit(mode);
+                refresh_vector_image(window, &self.current_path, &self.load_options);
+            }
+            Key::Character(c) if c == "R" || c == "r" => {
+                window.rotate(if c == "R" {
+                    Rotation::Left
+                } else {
+                    Rotation::Right
+                })?;
+                self.apply_fit(window);
+            }
+            Key::Character(c) if matches!(c.as_str(), "[" | "]" | "{" | "}") => {
+                let degrees = match c.as_str() {
+                    "[" => -1.0,
+                    "]" => 1.0,
+                    "{" => -0.1,
+                    _ => 0.1,
+                };
+                window.rotate_by(degrees)?;
+                self.apply_fit(window);
+            }
+            Key::Character(c) if c == "g" => window.toggle_grid()?,
+            Key::Character(c) if c == "m" => window.flip(Flip::Horizontal)?,
+            Key::Character(c) if c == "M" => window.flip(Flip::Vertical)?,
+            Key::Character(c) if c == "b" => {
+                let mut background = *window.background().read().unwrap();
+                background.mode = background.mode.next();
+                window.set_background(background)?;
+                let prompt = format!("Background: {}", background.mode.name());
+                window.show_prompt(Some(&prompt))?;
+            }
+            Key::Character(c) if c == "i" => {
+                let prompt = if window.toggle_nearest() {
+                    "Sharp pixels at any zoom"
+                } else {
+                    "Sharp pixels from --nearest-above"
+                };
+                window.show_prompt(Some(prompt))?;
+            }
+            Key::Character(c) if c == "v" => {
+                self.view_memory.policy = self.view_memory.policy.next();
+                let prompt = format!("View: {}", self.view_memory.policy.name());
+                window.show_prompt(Some(&prompt))?;
+            }
+            Key::Character(c) if c == "f" => window.toggle_fullscreen(),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Size the image again as kept, e.g. once turned.
+    fn apply_fit(&self, window: &Window) {
+        if let Some(mode) = self.fit_mode {
+            window.apply_fit(mode);
+        }
+    }
+
+    /// Start, stop or change the comparison, if `key` is for that.
+    fn handle_compare_key(&mut self, window: &Window, key: &event::Key) -> Result<bool> {
+        let event::Key::Character(c) = key else {
+            return Ok(false);
+        };
+        match c.as_str() {
+            "k" if !self.paired => {
+                if window.comparing() {
+                    window.stop_comparison()?;
+                    self.kept_preview = None;
+                } else {
+                    window.start_comparison();
+                    self.kept_preview = self.current_details.thumbnail.then_some(self.idx);
+                    self.idx = get_next_idx(self.idx, self.num_images, Direction::Right);
+                    // Even if it is the same, to show it beside
+                    self.reload = true;
+                }
+            }
+            "K" => {
+                if let Some(mode) = window.next_compare_mode() {
+                    let prompt = format!("Comparison: {}", mode.name());
+                    window.show_prompt(Some(&prompt))?;
+                }
+            }
+            "o" => window.swap_comparison(),
+            "A" => {
+                let options = window.update_diff_options(|options| {
+                    options.amplify = match options.amplify {
+                        a if a < 4.0 => 4.0,
+                        a if a < 16.0 => 16.0,
+                        a if a < 64.0 => 64.0,
+                        _ => 1.0,
+                    }
+                });
+                let prompt = format!("Difference: x{}", options.amplify);
+                window.show_prompt(Some(&prompt))?;
+            }
+            "H" => {
+                let options =
+                    window.update_diff_options(|options| options.heatmap = !options.heatmap);
+                window.show_prompt(Some(if options.heatmap {
+                    "Difference: heatmap"
+                } else {
+                    "Difference: channels"
+                }))?;
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Open the gallery, show or print information, save or quit.
+    fn handle_other_key(
+        &mut self,
+        window: &Window,
+        gallery: &mut Gallery,
+        key: &event::Key,
+    ) -> Result<ViewerAction> {
+        use event::{Key, NamedKey};
+        match key {
+            Key::Named(NamedKey::Escape) => return Ok(ViewerAction::Quit),
+            Key::Character(c) if c == "q" => return Ok(ViewerAction::Quit),
+            Key::Named(NamedKey::Tab) => window.toggle_hud()?,
+            Key::Character(c) if c == "F" => {
+                self.show_filmstrip = !self.show_filmstrip;
+                self.filmstrip.invalidate();
+                window.show_filmstrip(if self.show_filmstrip {
+                    self.filmstrip.update(self.idx, gallery)
+                } else {
+                    None
+                })?;
+            }
+            Key::Character(c) if c == "t" => {
+                gallery.open(self.idx, window.inner_size());
+                self.inspector = false;
+                window.show_inspection(None)?;
+            }
+            Key::Character(c) if c == "c" => {
+                println!("{}", self.current_path.display());
+            }
+            Key::Character(c) if c == "x" => {
+                self.inspector = !self.inspector;
+                let inspection = match self.cursor {
+                    Some(cursor) if self.inspector => window.inspect(cursor),
+                    _ => None,
+                };
+                window.show_inspection(inspection)?;
+            }
+            Key::Character(c) if c == "X" => {
+                if let Some(inspection) = self.cursor.and_then(|cursor| window.inspect(cursor)) {
+                    println!("{}: {}", self.current_path.display(), inspection);
+                }
+            }
+            Key::Character(c) if c == "s" => {
+                match save_straightened(
+                    &self.current_path,
+                    self.current_details.subimage,
+                    &window.view(),
+                    &self.load_options,
+                ) {
+                    Ok(target) => {
+                        window.show_prompt(Some(&format!("Saved {}", target.display())))?
+                    }
+                    Err(e) => error!("Cannot save {:?}: {}", self.current_path, e),
+                }
+            }
+            _ => {}
+        }
+        Ok(ViewerAction::Stay)
+    }
+
+    /// Show a new image, see `display`, or keep it for the pair compared.
+    fn display(&mut self, window: &Window, image_pair: ImagePair) -> Result<()> {
+        self.current_path = image_pair.0.clone();
+        self.current_details = image_pair.2.clone();
+        if self.paired && window.comparing() {
+            return window.set_kept_image(image_pair);
+        }
+        display(
+            window,
+            image_pair,
+            (self.idx, self.num_images),
+            self.fit_mode,
+            &mut self.view_memory,
+        )
+    }
+
+    /// Show that the current image failed to decode.
+    fn show_error(&mut self, window: &Window, path: &Path, error: &str) -> Result<()> {
+        self.current_path = path.to_owned();
+        self.current_details = ImageDetails::default();
+        self.view_memory.forget_shown();
+        if self.paired && window.comparing() {
+            window.show_kept_error(path, error)?;
+        } else {
+            window.show_error(path, error)?;
+        }
+        window.set_info(hud::describe(path, self.idx, self.num_images, None))
+    }
+}
+
+/// What was asked on the command line.
+struct Args {
+    inputs: Vec<String>,
+    limits: DecodeLimits,
+    sandbox: bool,
+    timeout: Option<Duration>,
+    skip_broken: bool,
+    fit_mode: FitMode,
+    view_policy: ViewPolicy,
+    /// Zoom percentage from which pixels are sharp, if any.
+    nearest_above: Option<f32>,
+    background: Background,
+    /// Time each image stays when blinking, if they swap on their own.
+    blink: Option<Duration>,
+    diff: DiffOptions,
+    /// Whether the inputs are two directories whose images are compared in
+    /// pairs.
+    pair: bool,
+    /// Whether to print the usage and exit.
+    help: bool,
+}
+
+fn option_value<'a>(it: &mut impl Iterator<Item = &'a String>, name: &str) -> Result<&'a str> {
+    it.next()
+        .map(|v| v.as_str())
+        .ok_or_else(|| anyhow!("Missing value for {}", name))
+}
+
+/// Parse `WIDTHxHEIGHT`, or a single number for both.
+fn parse_size(s: &str) -> Result<(u32, u32)> {
+    let parse = |n: &str| {
+        n.trim()
+            .parse::<u32>()
+            .map_err(|_| anyhow!("Invalid size {:?}", s))
+    };
+    match s.split_once(['x', 'X']) {
+        Some((w, h)) => Ok((parse(w)?, parse(h)?)),
+        None => parse(s).map(|n| (n, n)),
+    }
+}
+
+fn parse_percent(s: &str) -> Result<f32> {
+    s.trim_end_matches('%')
+        .parse()
+        .ok()
+        .filter(|p: &f32| p.is_finite() && *p > 0.0)
+        .ok_or_else(|| anyhow!("Invalid zoom percentage {:?}", s))
+}
+
+fn parse_args(args: &[String]) -> Result<Args> {
+    let mut parsed = Args {
+        inputs: Vec::new(),
+        limits: DecodeLimits::default(),
+        sandbox: false,
+        timeout: LoadOptions::default().timeout,
+        skip_broken: false,
+        fit_mode: FitMode::Fit,
+        view_policy: ViewPolicy::Reset,
+        nearest_above: Some(400.0),
+        background: Background::default(),
+        blink: Some(Duration::from_millis(500)),
+        diff: DiffOptions::default(),
+        pair: false,
+        help: false,
+    };
+
+    let mut it = args.iter().skip(1);
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--max-size" => {
+                let (w, h) = parse_size(option_value(&mut it, arg)?)?;
+                parsed.limits.max_width = w;
+                parsed.limits.max_height = h;
+            }
+            "--max-alloc" => {
+                parsed.limits.max_alloc = option_value(&mut it, arg)?
+                    .parse::<u64>()
+                    .ok()
+                    .and_then(|mib| mib.checked_mul(1024 * 1024))
+                    .ok_or_else(|| anyhow!("Invalid value for {}", arg))?;
+            }
+            "--fit" => {
+                let name = option_value(&mut it, arg)?;
+                parsed.fit_mode = FitMode::from_name(name)
+                    .ok_or_else(|| anyhow!("Invalid value for {}: {}", arg, name))?;
+            }
+            "--zoom" => {
+                parsed.fit_mode = FitMode::Percent(parse_percent(option_value(&mut it, arg)?)?)
+            }
+            "--view" => {
+                let name = option_value(&mut it, arg)?;
+                parsed.view_policy = ViewPolicy::from_name(name)
+                    .ok_or_else(|| anyhow!("Invalid value for {}: {}", arg, name))?;
+            }
+            "--nearest-above" => {
+                let percent = option_value(&mut it, arg)?;
+                parsed.nearest_above = match percent {
+                    "0" => None,
+                    _ => Some(parse_percent(percent)?),
+                };
+            }
+            "--background" => {
+                let value = option_value(&mut it, arg)?;
+                parsed.background.mode = match value {
+                    "checkerboard" => BackgroundMode::Checkerboard,
+                    "white" => BackgroundMode::White,
+                    "black" => BackgroundMode::Black,
+                    _ => {
+                        parsed.background.solid = parse_color(value)
+                            .ok_or_else(|| anyhow!("Invalid value for {}: {}", arg, value))?;
+                        BackgroundMode::Solid
+                    }
+                };
+            }
+            "--checker-size" => {
+                parsed.background.checker_size = option_value(&mut it, arg)?
+                    .parse()
+                    .ok()
+                    .filter(|size: &u32| *size > 0)
+                    .ok_or_else(|| anyhow!("Invalid value for {}", arg))?;
+            }
+            "--checker-colors" => {
+                let value = option_value(&mut it, arg)?;
+                let colors = value
+                    .split_once(',')
+                    .and_then(|(a, b)| Some([parse_color(a)?, parse_color(b)?]))
+                    .ok_or_else(|| anyhow!("Invalid value for {}: {}", arg, value))?;
+                parsed.background.checker_colors = colors;
+            }
+            "--blink" => {
+                let ms: u64 = option_value(&mut it, arg)?
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid value for {}", arg))?;
+                parsed.blink = (ms > 0).then(|| Duration::from_millis(ms));
+            }
+            "--diff-amplify" => {
+                parsed.diff.amplify = option_value(&mut it, arg)?
+                    .parse()
+                    .ok()
+                    .filter(|x: &f32| x.is_finite() && *x > 0.0)
+                    .ok_or_else(|| anyhow!("Invalid value for {}", arg))?;
+            }
+            "--diff-threshold" => {
+                let threshold = option_value(&mut it, arg)?
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid value for {}", arg))?;
+                parsed.diff.threshold = Some(threshold);
+            }
+            "--heatmap" => parsed.diff.heatmap = true,
+            "--pair" => parsed.pair = true,
+            "-h" | "--help" => parsed.help = true,
+            "--sandbox" => parsed.sandbox = true,
+            "--skip-broken" => parsed.skip_broken = true,
+            "--timeout" => {
+                let secs: f32 = option_value(&mut it, arg)?
+                    .parse()
+                    .ok()
+                    .filter(|s: &f32| s.is_finite() && *s >= 0.0)
+                    .ok_or_else(|| anyhow!("Invalid value for {}", arg))?;
+                parsed.timeout = (secs > 0.0).then(|| Duration::from_secs_f32(secs));
+            }
+            "--" => parsed.inputs.extend(it.by_ref().cloned()),
+            a if a.starts_with("--") => return Err(anyhow!("Unknown option {}", a)),
+            _ => parsed.inputs.push(arg.clone()),
+        }
+    }
+
+    Ok(parsed)
+}
+
+/// Images given on the command line or found in the directories given, and
+/// with --pair those they are compared with.
+fn find_images(parsed: &Args) -> Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let mut paths = Vec::new();
+    // The images compared with those of `paths`, with --pair
+    let mut candidates = Vec::new();
+
+    if parsed.pair {
+        let [first, second] = parsed.inputs.as_slice() else {
+            return Err(anyhow!("--pair takes two directories"));
+        };
+        let (first, second) = (Path::new(first), Path::new(second));
+        let pairing = Pairing::new(first, second)?;
+        for (dir, only) in [(first, &pairing.only_first), (second, &pairing.only_second)] {
+            for path in only {
+                println!("Only in {}: {}", dir.display(), path.display());
+            }
+        }
+        paths = pairing.both.iter().map(|path| first.join(path)).collect();
+        candidates = pairing.both.iter().map(|path| second.join(path)).collect();
+    }
+
+    for file_or_dir in parsed.inputs.iter().filter(|_| !parsed.pair) {
+        let arg_path = std::path::Path::new(file_or_dir);
+
+        if arg_path.is_file() {
+            paths.push(arg_path.canonicalize()?)
+        } else if arg_path.is_dir() {
+            for entry in arg_path
+                .read_dir()?
+                .filter_map(|x| x.ok())
+                .filter(|e| e.path().is_file())
+            {
+                let path = entry.path();
+                if is_supported_image(&path) {
+                    paths.push(path);
+                }
+            }
+        }
+    }
+
+    Ok((paths, candidates))
+}
+
+/// Print how to use the program, run as `bin`.
+fn print_usage(bin: &str) {
+    eprintln!(
+        "\
+Usage: {bin} [OPTIONS] [IMAGE or DIR]...
+       {bin} [OPTIONS] --pair DIR DIR
 
 A minimal image viewer.
 
@@ -74,45 +944,93 @@ NAVIGATION
   Shift+Space / h / p / N / Backspace  Previous image
   Home                   First image
   End                    Last image
+  PageDown / PageUp      Next / previous page (multi-page TIFF, ICO sizes, DDS mips)
+  t                      Thumbnail gallery: arrows, click to select, Enter or double click to open
+  F                      Toggle the strip of neighboring images, click one to go to it
 
 VIEW
-  Arrow keys             Pan
+  Arrow keys / drag      Pan
   = / scroll up          Zoom in
   - / scroll down        Zoom out
   Pinch                  Zoom (trackpad)
   r                      Rotate right
   R                      Rotate left
+  ] / [                  Tilt 1° right / left
+  }} / {{                Tilt 0.1° right / left
+  a DEG Enter            Rotate to DEG degrees
+  g                      Toggle the straightening grid
+  m / M                  Flip horizontally / vertically
   0                      Reset view
+  w / W                  Fit in window / fill window
+  1                      Actual size (100%)
+  z PCT Enter            Zoom to PCT percent
+  e / E                  Fit width / fit height
   f                      Toggle fullscreen
+  v                      Next view policy: reset, keep, remember
+  b                      Next background: checkerboard, solid, white, black
+  i                      Sharp pixels at any zoom / from --nearest-above
+
+COMPARE
+  k                      Compare: keep this image and show the next ones beside it, or stop
+  K                      Next comparison: side by side, above/below, wipe, blink, difference
+  o                      Swap sides, or the image shown when blinking
+  A                      Amplify the difference: x1, x4, x16, x64
+  H                      Toggle the difference as a heatmap
+  Drag the wipe line     Move it
 
 OTHER
+  Tab                    Toggle on-screen information
+  x                      Toggle the pixel inspector (coordinates and value under the cursor)
+  X                      Print the pixel under the cursor to stdout
   c                      Print current file path to stdout
-  q / Escape             Quit"
-        );
-        std::process::exit(if args.len() < 2 { 1 } else { 0 });
+  s                      Save as oriented, cropped if tilted (NAME-straightened.png)
+  q / Escape             Quit
+
+OPTIONS
+  --fit MODE             Initial sizing: fit, fill, actual, width or height
+  --zoom PCT             Initial zoom, in percent of the image pixels
+  --view POLICY          View of the next image: reset (default), keep or remember
+  --nearest-above PCT    Show sharp pixels from this zoom (default 400, 0 for never)
+  --background BG        Behind transparency: checkerboard (default), white, black or #RRGGBB
+  --checker-size PX      Size of the checkerboard squares (default 16)
+  --checker-colors A,B   Colors of the checkerboard squares (default #999999,#666666)
+  --blink MS             Time each image shows when blinking (default 500, 0 for on o only)
+  --diff-amplify X       Multiply differences by X to show them (default 1)
+  --diff-threshold N     Highlight pixels with a channel differing by more than N (0-255)
+  --heatmap              Show the difference as a heatmap
+  --pair                 Compare the images with the same path in two directories, in pairs;
+                         the ones in only one are listed on stdout
+  --max-size WxH         Refuse to decode larger images (default 65535x65535)
+  --max-alloc MIB        Memory a decoder may allocate (default 1024)
+  --sandbox              Decode each file in a restricted child process
+  --skip-broken          Skip files that fail to decode while navigating
+  --timeout SECS         Give up on decodes taking longer (default 30, 0 for never)"
+    );
+}
+
+fn main() {
+    // Decoding workers (see --sandbox) must not touch the display
+    let args: Vec<_> = std::env::args_os().collect();
+    if args.get(1).is_some_and(|a| sandbox::is_worker_arg(a)) {
+        sandbox::run_worker(&args[2..]);
     }
 
-    let mut paths = Vec::new();
+    show_image::run_context(run);
+}
 
-    for file_or_dir in args.iter().skip(1) {
-        let arg_path = std::path::Path::new(file_or_dir);
+fn run() -> Result<()> {
+    env_logger::Builder::from_default_env()
+        .format_timestamp_millis()
+        .init();
 
-        if arg_path.is_file() {
-            paths.push(arg_path.canonicalize()?)
-        } else if arg_path.is_dir() {
-            for entry in arg_path
-                .read_dir()?
-                .filter_map(|x| x.ok())
-                .filter(|e| e.path().is_file())
-            {
-                let path = entry.path();
-                if image::ImageFormat::from_path(&path).is_ok() {
-                    paths.push(path);
-                }
-            }
-        }
+    let args: Vec<_> = std::env::args().collect();
+    let parsed = parse_args(&args)?;
+    if parsed.inputs.is_empty() || parsed.help {
+        print_usage(&args[0]);
+        std::process::exit(if parsed.inputs.is_empty() { 1 } else { 0 });
     }
 
+    let (mut paths, candidates) = find_images(&parsed)?;
     paths.sort();
 
     if paths.is_empty() {
@@ -126,22 +1044,42 @@ OTHER
     let _cache = cache.clone();
 
     let (tx_img_idx_to_load, rx_img_idx_to_load) = channel::<Option<usize>>();
-    let (tx_img_idx_ready, rx_img_idx_ready) = channel::<Result<Option<usize>, String>>();
+    let (tx_img_idx_ready, rx_img_idx_ready) = channel::<Result<Option<usize>, (usize, String)>>();
 
     let try_get_next_image = || try_get_image(&rx_img_idx_ready, cache.clone());
 
     let window = generate_window()?;
+    window.set_nearest_above(parsed.nearest_above);
+    window.set_background(parsed.background)?;
+    window.update_diff_options(|options| *options = parsed.diff);
+
+    let inner_size = window.inner_size();
+    let load_options = LoadOptions {
+        svg_size: (inner_size.x, inner_size.y),
+        limits: parsed.limits,
+        sandbox: parsed.sandbox,
+        timeout: parsed.timeout,
+        skip_broken: parsed.skip_broken,
+    };
 
     let cp = window.window.context_proxy();
     let w2 = window.window.clone();
+    let decode_pool = ThreadPool::new(DECODE_THREADS);
 
+    let reader_background = window.background();
+    let reader_shows = window.reader_shows();
+    let reader_options = load_options.clone();
+    let reader_pool = decode_pool.clone();
+    let reader_paths = paths.clone();
     debug!("Start background thread to load images");
     cp.run_background_task(move || {
         start_file_reader(
             _cache,
-            paths,
+            reader_paths,
             0,
-            5,
+            CACHE_SIDE,
+            reader_options,
+            reader_pool,
             rx_img_idx_to_load,
             tx_img_idx_ready,
             w2,
@@ -153,116 +1091,313 @@ OTHER
             //      debug!("WAKE UP!");
             //    })
             // };
+            reader_background,
+            reader_shows,
         );
     });
 
+    // With --pair, the images compared are read along, and only shown by the
+    // comparison
+    let pair_reader = (!candidates.is_empty()).then(|| {
+        let cache: Arc<RwLock<Vec<FileStatus<ImagePair>>>> =
+            Arc::new(RwLock::new(vec![FileStatus::Unread; candidates.len()]));
+        let (tx_idx_to_load, rx_idx_to_load) = channel::<Option<usize>>();
+        let (tx_idx_ready, rx_idx_ready) = channel();
+        let reader_cache = cache.clone();
+        let reader_paths = candidates.clone();
+        let reader_options = load_options.clone();
+        let reader_pool = decode_pool.clone();
+        let reader_window = window.window.clone();
+        let reader_background = window.background();
+        cp.run_background_task(move || {
+            start_file_reader(
+                reader_cache,
+                reader_paths,
+                0,
+                CACHE_SIDE,
+                reader_options,
+                reader_pool,
+                rx_idx_to_load,
+                tx_idx_ready,
+                reader_window,
+                reader_background,
+                Arc::new(AtomicBool::new(false)),
+            );
+        });
+        (cache, tx_idx_to_load, rx_idx_ready)
+    });
+    let request = |idx: usize| {
+        tx_img_idx_to_load
+            .send(Some(idx))
+            .expect("Failed to send image request to internal thread");
+        if let Some((_, tx, _)) = &pair_reader {
+            tx.send(Some(idx))
+                .expect("Failed to send image request to internal thread");
+        }
+    };
+
+    let mut gallery = Gallery::new(
+        window.window.clone(),
+        paths.clone(),
+        cache.clone(),
+        CACHE_SIDE,
+        decode_pool,
+        load_options.clone(),
+        window.background(),
+        window.reader_shows(),
+    );
+
+    // Other pages of the current file are decoded off the event loop
+    let (tx_page, rx_page) = channel::<(usize, usize, Result<ImagePair>)>();
+    let mut viewer = Viewer {
+        idx: 0,
+        num_images,
+        reload: false,
+        paired: pair_reader.is_some(),
+        current_path: paths[0].clone(),
+        current_details: ImageDetails::default(),
+        load_options: load_options.clone(),
+        cursor: None,
+        fit_mode: Some(parsed.fit_mode),
+        view_memory: ViewMemory::new(parsed.view_policy),
+        wiping: false,
+        kept_preview: None,
+        filmstrip: Filmstrip::new(cache.clone(), CACHE_SIDE),
+        show_filmstrip: false,
+        inspector: false,
+        entry: None,
+        page_pool: ThreadPool::new(1),
+        tx_page,
+        loading_page: None,
+    };
+
     debug!("Request initial image");
     // let's start by displaying something
-    tx_img_idx_to_load
-        .send(Some(0))
-        .expect("Failed to send image request to internal thread");
-    let mut current_path;
+    request(0);
+    let mut prev_idx = 0;
+    // Where to go when skipping broken files
+    let mut skip_direction = Direction::Right;
     loop {
-        if let Some(image_pair) = try_get_next_image()? {
-            debug!("Display initial image");
-            current_path = image_pair.path_str().to_string();
-            window.set_image(image_pair)?;
-            break;
+        match try_get_next_image()? {
+            Some(Ok((_, image_pair))) => {
+                debug!("Display initial image");
+                viewer.display(&window, image_pair)?;
+                break;
+            }
+            Some(Err((err_idx, e))) if err_idx == viewer.idx => {
+                error!("Cannot load {:?}: {}", paths[err_idx], e);
+                let next = get_next_idx(err_idx, num_images, skip_direction);
+                if parsed.skip_broken && next != err_idx {
+                    viewer.idx = next;
+                    prev_idx = next;
+                    request(next);
+                    continue;
+                }
+                viewer.show_error(&window, &paths[err_idx], &e)?;
+                break;
+            }
+            _ => {}
         }
-        std::thread::sleep(std::time::Duration::from_millis(14));
+        std::thread::sleep(Duration::from_millis(14));
+    }
+    if pair_reader.is_some() {
+        // Keeping the first image of each pair, next to the second
+        window.start_comparison();
     }
 
-    let mut prev_idx = 0;
-    let mut idx = 0;
-
-    // Wait for the window to be closed or Escape to be pressed.
-    for event in window.event_channel()? {
-        if prev_idx != idx {
-            debug!("Request image at idx {}", idx);
-            tx_img_idx_to_load
-                .send(Some(idx))
-                .expect("Failed to send image request to internal thread");
-            prev_idx = idx;
+    // Wait for the window to be closed or Escape to be pressed, or else
+    // for the time to blink or redraw the overlays
+    let events = window.event_channel()?;
+    let mut next_blink: Option<Instant> = None;
+    loop {
+        let blink = parsed.blink.filter(|_| window.blinking());
+        next_blink = blink.map(|interval| next_blink.unwrap_or_else(|| Instant::now() + interval));
+        let poll = viewer.loading_page.map(|_| Instant::now() + PAGE_POLL);
+        let deadline = next_blink
+            .into_iter()
+            .chain(poll)
+            .chain(window.overlays_due())
+            .min();
+        let received = match deadline {
+            Some(at) => events.recv_timeout(at.saturating_duration_since(Instant::now())),
+            None => events.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        if let Ok((page_idx, page, result)) = rx_page.try_recv() {
+            // Unless another page or image was asked for meanwhile
+            if viewer.loading_page == Some((page_idx, page)) {
+                viewer.loading_page = None;
+                match result {
+                    Ok(image_pair) if page_idx == viewer.idx => {
+                        viewer.display(&window, image_pair)?;
+                    }
+                    Ok(_) => {}
+                    Err(e) => error!("Cannot load subimage {}: {}", page, e),
+                }
+            }
         }
 
-        if let Some(image_pair) = try_get_next_image()? {
-            current_path = image_pair.path_str().to_string();
-            debug!("Display image {:?}", current_path);
-            window.set_image(image_pair)?;
+        // Also while events arrive without changing the view
+        window.update_overlays()?;
+
+        let event = match received {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => {
+                if next_blink.is_some_and(|at| at <= Instant::now()) {
+                    window.swap_comparison();
+                    next_blink = None;
+                }
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        if prev_idx != viewer.idx || viewer.reload {
+            skip_direction = if viewer.idx > prev_idx || viewer.idx == 0 {
+                Direction::Right
+            } else {
+                Direction::Left
+            };
+            debug!("Request image at idx {}", viewer.idx);
+            request(viewer.idx);
+            prev_idx = viewer.idx;
+            viewer.reload = false;
         }
 
-        if let event::WindowEvent::KeyboardInput(event) = event {
-            if !event.is_synthetic && event.input.state.is_pressed() {
-                use event::{Key, NamedKey};
-                let key = &event.input.logical_key;
-                debug!("Keyboard event {:?}", key);
-                match key {
-                    Key::Named(NamedKey::Escape) => break,
-                    Key::Character(c) if c == "q" => break,
-                    Key::Named(NamedKey::ArrowUp) => {
-                        window.pan(0.0, 0.05);
-                    }
-                    Key::Named(NamedKey::ArrowDown) => {
-                        window.pan(0.0, -0.05);
-                    }
-                    Key::Named(NamedKey::ArrowRight) => {
-                        window.pan(-0.05, 0.0);
-                    }
-                    Key::Named(NamedKey::ArrowLeft) => {
-                        window.pan(0.05, 0.0);
-                    }
-                    Key::Character(ref c)
-                        if c == " " && event.modifiers.contains(event::ModifiersState::SHIFT) =>
-                    {
-                        idx = get_next_idx(idx, num_images, Direction::Left);
-                    }
-                    Key::Character(ref c) if c == " " => {
-                        idx = get_next_idx(idx, num_images, Direction::Right);
-                    }
-                    Key::Character(c) if c == "l" || c == "n" => {
-                        idx = get_next_idx(idx, num_images, Direction::Right);
-                    }
-                    Key::Named(NamedKey::Backspace) => {
-                        idx = get_next_idx(idx, num_images, Direction::Left);
-                    }
-                    Key::Character(c) if c == "h" || c == "p" || c == "N" => {
-                        idx = get_next_idx(idx, num_images, Direction::Left);
-                    }
-                    Key::Named(NamedKey::Home) => {
-                        idx = get_next_idx(idx, num_images, Direction::First);
-                    }
-                    Key::Named(NamedKey::End) => {
-                        idx = get_next_idx(idx, num_images, Direction::Last);
-                    }
-                    Key::Character(c) if c == "0" => {
-                        window.reset_image();
-                    }
-                    Key::Character(c) if c == "-" => {
-                        window.scale_down();
-                    }
-                    Key::Character(c) if c == "=" => {
-                        window.scale_up();
-                    }
-                    Key::Character(c) if c == "R" => {
-                        window.rotate(Rotation::Left);
-                    }
-                    Key::Character(c) if c == "r" => {
-                        window.rotate(Rotation::Right);
-                    }
-                    Key::Character(c) if c == "f" => {
-                        window.toggle_fullscreen();
-                    }
-                    Key::Character(c) if c == "c" => {
-                        println!("{}", current_path);
-                    }
-                    _ => (),
+        if gallery.is_open() {
+            // Thumbnails can be made from what the reader was still busy with
+            if try_get_next_image()?.is_some() {
+                gallery.refresh();
+            }
+            match handle_gallery_event(&mut gallery, &event) {
+                GalleryAction::Stay => {}
+                GalleryAction::Open(selected) => {
+                    viewer.idx = selected;
+                    viewer.reload = true;
+                }
+                GalleryAction::Quit => break,
+            }
+            continue;
+        }
+
+        if let Some(kept) = viewer.kept_preview {
+            let full = match cache.read().unwrap().get(kept) {
+                Some(FileStatus::Read(image_pair)) => Some(image_pair.clone()),
+                Some(FileStatus::Thumbnail(_) | FileStatus::Reading) => None,
+                // Nothing better to keep
+                _ => {
+                    viewer.kept_preview = None;
+                    None
+                }
+            };
+            if let Some(image_pair) = full {
+                viewer.kept_preview = None;
+                window.set_kept_image(image_pair)?;
+            }
+        }
+
+        match try_get_next_image()? {
+            Some(Ok((_, image_pair))) => {
+                debug!("Display image {:?}", image_pair.0);
+                viewer.display(&window, image_pair)?;
+            }
+            Some(Err((err_idx, e))) if err_idx == viewer.idx => {
+                error!("Cannot load {:?}: {}", paths[viewer.idx], e);
+                let next = get_next_idx(viewer.idx, num_images, skip_direction);
+                if parsed.skip_broken && next != viewer.idx {
+                    debug!("Skip to idx {}", next);
+                    viewer.idx = next;
+                    prev_idx = next;
+                    request(next);
+                } else {
+                    viewer.show_error(&window, &paths[viewer.idx], &e)?;
+                }
+            }
+            Some(Err((err_idx, e))) => {
+                debug!("Ignore error of {:?}, we moved on: {}", paths[err_idx], e);
+            }
+            None => {}
+        }
+
+        if let Some((cache, _, rx)) = &pair_reader {
+            match try_get_image(rx, cache.clone())? {
+                Some(Ok((image_idx, image_pair))) if image_idx == viewer.idx => {
+                    window.set_image(image_pair)?
                 }
+                Some(Ok((image_idx, _))) => {
+                    debug!("Ignore {:?}, we moved on", candidates[image_idx]);
+                }
+                Some(Err((err_idx, e))) if err_idx == viewer.idx => {
+                    error!("Cannot load {:?}: {}", candidates[viewer.idx], e);
+                    window.show_error(&candidates[viewer.idx], &e)?;
+                }
+                Some(Err((err_idx, e))) => {
+                    debug!(
+                        "Ignore error of {:?}, we moved on: {}",
+                        candidates[err_idx], e
+                    );
+                }
+                None => {}
             }
         }
+
+        if viewer.show_filmstrip {
+            if let Some(strip) = viewer.filmstrip.update(viewer.idx, &gallery) {
+                window.show_filmstrip(Some(strip))?;
+            }
+        }
+
+        match viewer.handle_event(&window, &mut gallery, &event)? {
+            ViewerAction::Stay => {}
+            ViewerAction::Quit => break,
+        }
     }
 
     debug!("Exiting. Wait for threads to close");
     tx_img_idx_to_load.send(None).unwrap();
+    if let Some((_, tx, _)) = &pair_reader {
+        tx.send(None).unwrap();
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_args() {
+        let args = |list: &[&str]| {
+            let list: Vec<String> = std::iter::once("minimg")
+                .chain(list.iter().copied())
+                .map(String::from)
+                .collect();
+            parse_args(&list)
+        };
+        assert!(args(&["--help"]).unwrap().help);
+        assert!(args(&["-h", "a.png"]).unwrap().help);
+        let parsed = args(&["--pair", "a", "b"]).unwrap();
+        assert!(parsed.pair && !parsed.help);
+        assert_eq!(vec!["a", "b"], parsed.inputs);
+        // Only options before `--`
+        let parsed = args(&["--", "--help"]).unwrap();
+        assert!(!parsed.help);
+        assert_eq!(vec!["--help"], parsed.inputs);
+        assert_eq!(
+            Some("Unknown option --nope".to_string()),
+            args(&["--nope"]).err().map(|e| e.to_string())
+        );
+
+        assert_eq!(
+            2 << 20,
+            args(&["--max-alloc", "2"]).unwrap().limits.max_alloc
+        );
+        assert_eq!(
+            Some("Invalid value for --max-alloc".to_string()),
+            args(&["--max-alloc", "18000000000000"])
+                .err()
+                .map(|e| e.to_string())
+        );
+    }
+}
@@ -0,0 +1,562 @@
+use image::imageops::{interpolate_bilinear, FilterType};
+use image::{DynamicImage, Rgba, RgbaImage};
+use show_image::glam::{UVec2, Vec2};
+
+use crate::background::Background;
+use crate::diff::{difference, DiffOptions, DiffStats};
+use crate::font;
+use crate::view::ViewState;
+
+/// Pixels between the panes, and width of the wipe line.
+const DIVIDER: f32 = 2.0;
+const DIVIDER_COLOR: Rgba<u8> = Rgba([128, 128, 128, 255]);
+const WIPE_COLOR: Rgba<u8> = Rgba([255, 255, 255, 255]);
+const MESSAGE_COLOR: Rgba<u8> = Rgba([235, 235, 235, 255]);
+
+/// How two images are put together on screen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompareMode {
+    SideBySide,
+    AboveBelow,
+    /// One over the other, the first on the left of a line and the second
+    /// on the right
+    Wipe,
+    /// Only the first, swapped with the second over time
+    Blink,
+    /// How much they differ, see `DiffOptions`
+    Difference,
+}
+
+impl CompareMode {
+    /// The mode after this one, to cycle through them.
+    pub fn next(&self) -> CompareMode {
+        match self {
+            CompareMode::SideBySide => CompareMode::AboveBelow,
+            CompareMode::AboveBelow => CompareMode::Wipe,
+            CompareMode::Wipe => CompareMode::Blink,
+            CompareMode::Blink => CompareMode::Difference,
+            CompareMode::Difference => CompareMode::SideBySide,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CompareMode::SideBySide => "side by side",
+            CompareMode::AboveBelow => "above/below",
+            CompareMode::Wipe => "wipe",
+            CompareMode::Blink => "blink",
+            CompareMode::Difference => "difference",
+        }
+    }
+
+    /// Top left corner and size of the panes of a window of size `window`,
+    /// all showing images with the same view: one per image side by side or
+    /// above and below, else the whole window.
+    pub fn panes(&self, window: Vec2) -> Vec<(Vec2, Vec2)> {
+        match self {
+            CompareMode::SideBySide => {
+                let size = Vec2::new(((window.x - DIVIDER) / 2.0).floor().max(1.0), window.y);
+                vec![(Vec2::ZERO, size), (Vec2::new(size.x + DIVIDER, 0.0), size)]
+            }
+            CompareMode::AboveBelow => {
+                let size = Vec2::new(window.x, ((window.y - DIVIDER) / 2.0).floor().max(1.0));
+                vec![(Vec2::ZERO, size), (Vec2::new(0.0, size.y + DIVIDER), size)]
+            }
+            CompareMode::Wipe | CompareMode::Blink | CompareMode::Difference => {
+                vec![(Vec2::ZERO, window)]
+            }
+        }
+    }
+}
+
+/// Part of the window showing one of the images.
+struct Region {
+    /// Top left and bottom right corners, in window pixels.
+    min: UVec2,
+    max: UVec2,
+    /// Top left corner and size of the pane it is part of.
+    pane: (Vec2, Vec2),
+    /// Which image, in the order they are on screen.
+    image: usize,
+}
+
+/// What is on screen, see `Comparison::frame`.
+#[derive(Clone, Copy, PartialEq)]
+enum Shown {
+    Canvas(Drawn),
+    /// The image blinked to, or the difference.
+    Image {
+        mode: CompareMode,
+        swapped: bool,
+    },
+}
+
+/// What a canvas rendered by `Comparison::render` shows, besides the
+/// images.
+#[derive(Clone, Copy, PartialEq)]
+struct Drawn {
+    view: ViewState,
+    window: UVec2,
+    color: Rgba<u8>,
+    smooth: bool,
+    mode: CompareMode,
+    swapped: bool,
+    wipe: f32,
+}
+
+/// Two images shown together, with zoom, pan and rotation locked: one
+/// pinned, and the other, which changes while browsing. Both change when
+/// browsing pairs.
+///
+/// Images may have different sizes, e.g. renders at different resolutions.
+/// Both show the same region relative to their size, and the view is about
+/// the pinned one.
+pub struct Comparison {
+    pub mode: CompareMode,
+    /// Whether the pinned image is on the right (or below) rather than on
+    /// the left (or above), or hidden when blinking.
+    pub swapped: bool,
+    /// Where the wipe line is across the window, from 0 to 1.
+    pub wipe: f32,
+    pinned: Compared,
+    other: Option<Compared>,
+    diff_options: DiffOptions,
+    /// The difference of the images, once computed for the options.
+    difference: Option<std::result::Result<(RgbaImage, DiffStats), String>>,
+    /// What the last frame showed, None if the images changed since.
+    drawn: Option<Shown>,
+}
+
+/// What to put on screen for the images compared.
+pub enum Frame {
+    /// The same as last time.
+    Unchanged,
+    /// An image with its name, shown like any other: uploaded once, then
+    /// moved by the view.
+    Image(String, DynamicImage),
+    /// The images drawn as the view shows them, to put on screen as is.
+    Canvas(RgbaImage),
+}
+
+struct Compared {
+    name: String,
+    image: DynamicImage,
+    /// The image over the background, ready to be drawn.
+    shown: RgbaImage,
+}
+
+impl Compared {
+    fn new(name: String, image: DynamicImage, background: &Background, pane: Vec2) -> Self {
+        let shown = background.apply(image.clone(), pane).to_rgba8();
+        Compared { name, image, shown }
+    }
+}
+
+impl Comparison {
+    /// Compare with `image`, named `name`, over `background` for panes of
+    /// size `pane`.
+    pub fn new(
+        name: String,
+        image: DynamicImage,
+        background: &Background,
+        pane: Vec2,
+    ) -> Comparison {
+        Comparison {
+            mode: CompareMode::SideBySide,
+            swapped: false,
+            wipe: 0.5,
+            pinned: Compared::new(name, image, background, pane),
+            other: None,
+            diff_options: DiffOptions::default(),
+            difference: None,
+            drawn: None,
+        }
+    }
+
+    /// Show `image`, named `name`, next to the pinned one.
+    pub fn set_other(
+        &mut self,
+        name: String,
+        image: DynamicImage,
+        background: &Background,
+        pane: Vec2,
+    ) {
+        self.other = Some(Compared::new(name, image, background, pane));
+        self.difference = None;
+        self.drawn = None;
+    }
+
+    /// Pin `image`, named `name`, instead, e.g. the full image once its
+    /// preview was pinned, or the next one of a pair.
+    pub fn set_pinned(
+        &mut self,
+        name: String,
+        image: DynamicImage,
+        background: &Background,
+        pane: Vec2,
+    ) {
+        self.pinned = Compared::new(name, image, background, pane);
+        self.difference = None;
+        self.drawn = None;
+    }
+
+    /// Show the difference of the images as `options` say.
+    pub fn set_diff_options(&mut self, options: DiffOptions) {
+        if options != self.diff_options {
+            self.diff_options = options;
+            self.difference = None;
+            self.drawn = None;
+        }
+    }
+
+    /// Compute the difference of the images if it is to be shown and isn't
+    /// yet.
+    pub fn update_difference(&mut self) {
+        if self.mode != CompareMode::Difference || self.difference.is_some() {
+            return;
+        }
+        if let Some(other) = &self.other {
+            let computed = difference(&self.pinned.image, &other.image, &self.diff_options);
+            self.difference = Some(computed.map_err(|e| e.to_string()));
+            self.drawn = None;
+        }
+    }
+
+    /// Put the images over `background` instead.
+    pub fn set_background(&mut self, background: &Background, pane: Vec2) {
+        for compared in std::iter::once(&mut self.pinned).chain(self.other.as_mut()) {
+            compared.shown = background.apply(compared.image.clone(), pane).to_rgba8();
+        }
+        self.drawn = None;
+    }
+
+    /// The image browsed to and its name, or the pinned one if none was.
+    pub fn into_last(self) -> (String, DynamicImage) {
+        let last = self.other.unwrap_or(self.pinned);
+        (last.name, last.image)
+    }
+
+    /// Size in pixels of the pinned image, which the view is about.
+    pub fn size(&self) -> Vec2 {
+        let (width, height) = self.pinned.shown.dimensions();
+        Vec2::new(width as f32, height as f32)
+    }
+
+    /// Names of the images in the order they are on screen.
+    pub fn names(&self) -> [&str; 2] {
+        let other = self.other.as_ref().map_or("", |other| other.name.as_str());
+        if self.swapped {
+            [other, &self.pinned.name]
+        } else {
+            [&self.pinned.name, other]
+        }
+    }
+
+    /// What the window title says about the images.
+    pub fn title(&self) -> String {
+        match self.mode {
+            CompareMode::Blink => format!("{} (blink)", self.names()[0]),
+            CompareMode::Difference => match &self.difference {
+                Some(Ok((_, stats))) => {
+                    format!("{}: {}", self.names().join(" | "), stats.describe())
+                }
+                Some(Err(e)) => format!("{}: {}", self.names().join(" | "), e),
+                None => self.names().join(" | "),
+            },
+            _ => self.names().join(" | "),
+        }
+    }
+
+    /// Whether a single image is shown, with the whole window for pane:
+    /// when blinking, or the difference once computed.
+    pub fn single(&self) -> bool {
+        match self.mode {
+            CompareMode::Blink => !self.swapped || self.other.is_some(),
+            CompareMode::Difference => matches!(self.difference, Some(Ok(_))),
+            _ => false,
+        }
+    }
+
+    /// What to put on screen to show the images as `view` does, in a window
+    /// of size `window`, see `render`.
+    pub fn frame(
+        &mut self,
+        view: &ViewState,
+        window: UVec2,
+        color: Rgba<u8>,
+        smooth: bool,
+    ) -> Frame {
+        if !self.single() {
+            return self
+                .render(view, window, color, smooth)
+                .map_or(Frame::Unchanged, Frame::Canvas);
+        }
+        let shown = Shown::Image {
+            mode: self.mode,
+            swapped: self.swapped,
+        };
+        if self.drawn == Some(shown) {
+            return Frame::Unchanged;
+        }
+        self.drawn = Some(shown);
+
+        if let (CompareMode::Difference, Some(Ok((difference, _)))) = (self.mode, &self.difference)
+        {
+            let name = self.names().join(" - ");
+            return Frame::Image(name, DynamicImage::ImageRgba8(difference.clone()));
+        }
+        let front = match (self.swapped, &self.other) {
+            (true, Some(other)) => other,
+            _ => &self.pinned,
+        };
+        // Stretched like the panes show it, for the view to be the same
+        let (width, height) = self.pinned.shown.dimensions();
+        let image = if (front.image.width(), front.image.height()) == (width, height) {
+            front.image.clone()
+        } else {
+            front
+                .image
+                .resize_exact(width, height, FilterType::Triangle)
+        };
+        Frame::Image(front.name.clone(), image)
+    }
+
+    /// The images in the order they are on screen.
+    fn images(&self) -> [Option<&RgbaImage>; 2] {
+        let other = self.other.as_ref().map(|other| &other.shown);
+        if self.swapped {
+            [other, Some(&self.pinned.shown)]
+        } else {
+            [Some(&self.pinned.shown), other]
+        }
+    }
+
+    /// Where each image goes in a window of size `window`.
+    fn regions(&self, window: UVec2) -> Vec<Region> {
+        let panes = self.mode.panes(window.as_vec2());
+        let whole = |image| Region {
+            min: UVec2::ZERO,
+            max: window,
+            pane: panes[0],
+            image,
+        };
+        match self.mode {
+            CompareMode::SideBySide | CompareMode::AboveBelow => panes
+                .into_iter()
+                .enumerate()
+                .map(|(image, (origin, size))| Region {
+                    min: origin.as_uvec2(),
+                    max: (origin + size).as_uvec2().min(window),
+                    pane: (origin, size),
+                    image,
+                })
+                .collect(),
+            CompareMode::Wipe => {
+                let line = self.wipe_line(window.x as f32) as u32;
+                vec![
+                    Region {
+                        max: UVec2::new(line, window.y),
+                        ..whole(0)
+                    },
+                    Region {
+                        min: UVec2::new(line, 0),
+                        ..whole(1)
+                    },
+                ]
+            }
+            CompareMode::Blink | CompareMode::Difference => vec![whole(0)],
+        }
+    }
+
+    /// Where the wipe line is, in a window `width` pixels wide.
+    pub fn wipe_line(&self, width: f32) -> f32 {
+        (self.wipe.clamp(0.0, 1.0) * width).round()
+    }
+
+    /// The images as `view` shows them, in a window of size `window`.
+    /// Pixels are interpolated unless `smooth` is false. None if that is
+    /// what the last canvas rendered already shows.
+    pub fn render(
+        &mut self,
+        view: &ViewState,
+        window: UVec2,
+        color: Rgba<u8>,
+        smooth: bool,
+    ) -> Option<RgbaImage> {
+        let drawn = Drawn {
+            view: *view,
+            window,
+            color,
+            smooth,
+            mode: self.mode,
+            swapped: self.swapped,
+            wipe: self.wipe,
+        };
+        if self.drawn == Some(Shown::Canvas(drawn)) {
+            return None;
+        }
+        self.drawn = Some(Shown::Canvas(drawn));
+
+        let mut canvas = RgbaImage::from_pixel(window.x, window.y, DIVIDER_COLOR);
+        let size = self.size();
+        let images = match (self.mode, &self.difference) {
+            (CompareMode::Difference, Some(Ok((shown, _)))) => [Some(shown), None],
+            (CompareMode::Difference, _) => [None, None],
+            _ => self.images(),
+        };
+        for region in self.regions(window) {
+            let (origin, pane) = region.pane;
+            let to_image = view.transform(size, pane).inverse();
+            let image = images[region.image];
+            for y in region.min.y..region.max.y {
+                for x in region.min.x..region.max.x {
+                    let position = Vec2::new(x as f32, y as f32) + 0.5 - origin;
+                    let point = to_image.transform_point2(position / pane);
+                    let pixel = image.and_then(|image| sample(image, point, smooth));
+                    canvas.put_pixel(x, y, pixel.unwrap_or(color));
+                }
+            }
+        }
+
+        if self.mode == CompareMode::Wipe {
+            let line = self.wipe_line(window.x as f32) as i64 - DIVIDER as i64 / 2;
+            for y in 0..window.y {
+                for x in line.max(0)..(line + DIVIDER as i64).min(window.x as i64) {
+                    canvas.put_pixel(x as u32, y, WIPE_COLOR);
+                }
+            }
+        }
+        if let (CompareMode::Difference, Some(Err(e))) = (self.mode, &self.difference) {
+            let x = (window.x as i64 - font::text_width(e, 2) as i64) / 2;
+            let y = (window.y as i64 - font::line_height(2) as i64) / 2;
+            font::draw_text(&mut canvas, x.max(0), y, e, 2, MESSAGE_COLOR);
+        }
+        Some(canvas)
+    }
+}
+
+/// The color of `image` at `point` (normalized), if it is on the image.
+fn sample(image: &RgbaImage, point: Vec2, smooth: bool) -> Option<Rgba<u8>> {
+    if point.cmplt(Vec2::ZERO).any() || point.cmpge(Vec2::ONE).any() {
+        return None;
+    }
+    let size = Vec2::new(image.width() as f32, image.height() as f32);
+    let texel = point * size;
+    if smooth {
+        // Pixel centers, kept inside at the edges
+        let center = (texel - 0.5).clamp(Vec2::ZERO, size - 1.0);
+        interpolate_bilinear(image, center.x, center.y)
+    } else {
+        Some(*image.get_pixel(texel.x as u32, texel.y as u32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_panes_share_the_view() {
+        let left = RgbaImage::from_pixel(4, 2, Rgba([255, 0, 0, 255]));
+        let right = RgbaImage::from_pixel(8, 4, Rgba([0, 0, 255, 255]));
+        let background = Background::default();
+        let window = UVec2::new(82, 40);
+        let (_, pane) = CompareMode::SideBySide.panes(window.as_vec2())[0];
+        assert_eq!(Vec2::new(40.0, 40.0), pane);
+
+        let mut comparison = Comparison::new("a".into(), left.into(), &background, pane);
+        comparison.set_other("b".into(), right.into(), &background, pane);
+        let mut view = ViewState::default();
+        view.fit(crate::view::FitMode::Fit, comparison.size(), pane);
+
+        let black = Rgba([0, 0, 0, 255]);
+        let canvas = comparison.render(&view, window, black, false).unwrap();
+        // Both cover the middle of their pane alike, with the divider between
+        assert_eq!(Rgba([255, 0, 0, 255]), *canvas.get_pixel(20, 20));
+        assert_eq!(DIVIDER_COLOR, *canvas.get_pixel(40, 20));
+        assert_eq!(Rgba([0, 0, 255, 255]), *canvas.get_pixel(62, 20));
+        assert_eq!(black, *canvas.get_pixel(62, 2));
+        // Nothing changed to draw again
+        assert!(comparison.render(&view, window, black, false).is_none());
+
+        comparison.swapped = true;
+        assert_eq!(["b", "a"], comparison.names());
+        let canvas = comparison.render(&view, window, black, false).unwrap();
+        assert_eq!(Rgba([0, 0, 255, 255]), *canvas.get_pixel(20, 20));
+    }
+
+    #[test]
+    fn test_wipe() {
+        let first = RgbaImage::from_pixel(10, 10, Rgba([255, 0, 0, 255]));
+        let second = RgbaImage::from_pixel(10, 10, Rgba([0, 0, 255, 255]));
+        let background = Background::default();
+        let window = UVec2::new(100, 100);
+        let mut comparison =
+            Comparison::new("a".into(), first.into(), &background, window.as_vec2());
+        comparison.set_other("b".into(), second.into(), &background, window.as_vec2());
+        comparison.mode = CompareMode::Wipe;
+        comparison.wipe = 0.3;
+        let mut view = ViewState::default();
+        view.fit(
+            crate::view::FitMode::Fit,
+            comparison.size(),
+            window.as_vec2(),
+        );
+
+        let black = Rgba([0, 0, 0, 255]);
+        let canvas = comparison.render(&view, window, black, false).unwrap();
+        assert_eq!(Rgba([255, 0, 0, 255]), *canvas.get_pixel(28, 50));
+        assert_eq!(WIPE_COLOR, *canvas.get_pixel(30, 50));
+        assert_eq!(Rgba([0, 0, 255, 255]), *canvas.get_pixel(31, 50));
+
+        // Blinking shows only the first, until swapped
+        comparison.mode = CompareMode::Blink;
+        let canvas = comparison.render(&view, window, black, false).unwrap();
+        assert_eq!(Rgba([255, 0, 0, 255]), *canvas.get_pixel(80, 50));
+        comparison.swapped = true;
+        assert_eq!("b (blink)", comparison.title());
+    }
+
+    #[test]
+    fn test_single_frames() {
+        let first = RgbaImage::from_pixel(10, 10, Rgba([255, 0, 0, 255]));
+        let second = RgbaImage::from_pixel(20, 20, Rgba([0, 0, 255, 255]));
+        let background = Background::default();
+        let window = UVec2::new(100, 100);
+        let mut comparison =
+            Comparison::new("a".into(), first.into(), &background, window.as_vec2());
+        comparison.set_other("b".into(), second.into(), &background, window.as_vec2());
+        let view = ViewState::default();
+        let black = Rgba([0, 0, 0, 255]);
+
+        comparison.mode = CompareMode::Blink;
+        assert!(matches!(
+            comparison.frame(&view, window, black, false),
+            Frame::Image(name, _) if name == "a"
+        ));
+        // Moved by the view from then on
+        let mut zoomed = view;
+        zoomed.zoom = 3.0;
+        assert!(matches!(
+            comparison.frame(&zoomed, window, black, false),
+            Frame::Unchanged
+        ));
+        // The other one at the size of the first, for the view to fit both
+        comparison.swapped = true;
+        match comparison.frame(&zoomed, window, black, false) {
+            Frame::Image(name, image) => {
+                assert_eq!("b", name);
+                assert_eq!((10, 10), (image.width(), image.height()));
+            }
+            _ => panic!("expected the other image"),
+        }
+
+        // Images of different sizes have no difference, which is drawn
+        comparison.mode = CompareMode::Difference;
+        comparison.update_difference();
+        assert!(matches!(
+            comparison.frame(&zoomed, window, black, false),
+            Frame::Canvas(_)
+        ));
+    }
+}
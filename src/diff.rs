@@ -0,0 +1,193 @@
+use anyhow::{anyhow, Result};
+use image::{DynamicImage, Rgba, RgbaImage};
+
+/// Color of the pixels differing by more than the threshold.
+const HIGHLIGHT: Rgba<u8> = Rgba([255, 0, 255, 255]);
+
+/// How differences are shown.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DiffOptions {
+    /// Differences are multiplied by this to show.
+    pub amplify: f32,
+    /// Show the largest difference of each pixel as a color from black
+    /// through red and yellow to white, rather than the difference of each
+    /// channel.
+    pub heatmap: bool,
+    /// Pixels with a channel differing by more than this are highlighted.
+    pub threshold: Option<u8>,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        DiffOptions {
+            amplify: 1.0,
+            heatmap: false,
+            threshold: None,
+        }
+    }
+}
+
+/// How much two images differ, over their color channels (and alpha, if
+/// either has some) at 8 bits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DiffStats {
+    /// Largest difference of a channel.
+    pub max: u8,
+    /// Mean absolute difference of the channels.
+    pub mean: f64,
+    /// Peak signal-to-noise ratio in dB, infinite for equal images.
+    pub psnr: f64,
+    /// Pixels with any channel differing.
+    pub differing: u64,
+    /// Pixels with a channel differing by more than the threshold.
+    pub exceeding: Option<u64>,
+    pub pixels: u64,
+}
+
+impl DiffStats {
+    /// The numbers, for humans.
+    pub fn describe(&self) -> String {
+        let percent = |count: u64| 100.0 * count as f64 / self.pixels.max(1) as f64;
+        let mut text = format!(
+            "max {}, mean {:.3}, PSNR {}, {} pixels differ ({:.2}%)",
+            self.max,
+            self.mean,
+            if self.psnr.is_finite() {
+                format!("{:.2} dB", self.psnr)
+            } else {
+                "∞".to_string()
+            },
+            self.differing,
+            percent(self.differing)
+        );
+        if let Some(exceeding) = self.exceeding {
+            text.push_str(&format!(
+                ", {} over threshold ({:.2}%)",
+                exceeding,
+                percent(exceeding)
+            ));
+        }
+        text
+    }
+}
+
+/// The difference of `a` and `b` as an image to show, with its statistics.
+/// They must have the same size.
+pub fn difference(
+    a: &DynamicImage,
+    b: &DynamicImage,
+    options: &DiffOptions,
+) -> Result<(RgbaImage, DiffStats)> {
+    if (a.width(), a.height()) != (b.width(), b.height()) {
+        return Err(anyhow!(
+            "Cannot compare images of different sizes: {}x{} and {}x{}",
+            a.width(),
+            a.height(),
+            b.width(),
+            b.height()
+        ));
+    }
+    let channels = if a.color().has_alpha() || b.color().has_alpha() {
+        4
+    } else {
+        3
+    };
+    let (a, b) = (a.to_rgba8(), b.to_rgba8());
+
+    let mut shown = RgbaImage::new(a.width(), a.height());
+    let (mut max, mut sum, mut squares, mut differing, mut exceeding) = (0, 0u64, 0u64, 0, 0);
+    for ((a, b), pixel) in a.pixels().zip(b.pixels()).zip(shown.pixels_mut()) {
+        let mut delta = [0u8; 4];
+        for i in 0..channels {
+            delta[i] = a[i].abs_diff(b[i]);
+            sum += delta[i] as u64;
+            squares += delta[i] as u64 * delta[i] as u64;
+        }
+        let largest = delta.into_iter().max().unwrap_or(0);
+        max = max.max(largest);
+        differing += (largest > 0) as u64;
+
+        let amplified = |value: u8| (value as f32 * options.amplify).min(255.0) as u8;
+        *pixel = if options
+            .threshold
+            .is_some_and(|threshold| largest > threshold)
+        {
+            exceeding += 1;
+            HIGHLIGHT
+        } else if options.heatmap {
+            heat(amplified(largest))
+        } else {
+            Rgba([
+                amplified(delta[0]),
+                amplified(delta[1]),
+                amplified(delta[2]),
+                255,
+            ])
+        };
+    }
+
+    let samples = (a.width() as u64 * a.height() as u64 * channels as u64).max(1);
+    let mse = squares as f64 / samples as f64;
+    let stats = DiffStats {
+        max,
+        mean: sum as f64 / samples as f64,
+        psnr: 10.0 * (255.0 * 255.0 / mse).log10(),
+        differing,
+        exceeding: options.threshold.map(|_| exceeding),
+        pixels: a.width() as u64 * a.height() as u64,
+    };
+    Ok((shown, stats))
+}
+
+/// Black through red and yellow to white, as `value` goes up.
+fn heat(value: u8) -> Rgba<u8> {
+    let value = value as u32 * 3;
+    let channel = |offset: u32| value.saturating_sub(offset).min(255) as u8;
+    Rgba([channel(0), channel(255), channel(510), 255])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+
+    #[test]
+    fn test_difference() {
+        let a = RgbImage::from_pixel(2, 2, image::Rgb([100, 100, 100]));
+        let mut b = a.clone();
+        b.put_pixel(1, 0, image::Rgb([110, 100, 97]));
+        let (a, b) = (DynamicImage::from(a), DynamicImage::from(b));
+
+        let options = DiffOptions {
+            amplify: 2.0,
+            ..Default::default()
+        };
+        let (shown, stats) = difference(&a, &b, &options).unwrap();
+        assert_eq!(Rgba([20, 0, 6, 255]), *shown.get_pixel(1, 0));
+        assert_eq!(Rgba([0, 0, 0, 255]), *shown.get_pixel(0, 0));
+        assert_eq!(10, stats.max);
+        assert_eq!(1, stats.differing);
+        assert!((stats.mean - 13.0 / 12.0).abs() < 1e-9);
+        // MSE of 109 / 12
+        assert!((stats.psnr - 38.548).abs() < 1e-3);
+        assert_eq!(None, stats.exceeding);
+
+        let options = DiffOptions {
+            threshold: Some(5),
+            heatmap: true,
+            ..Default::default()
+        };
+        let (shown, stats) = difference(&a, &b, &options).unwrap();
+        assert_eq!(HIGHLIGHT, *shown.get_pixel(1, 0));
+        assert_eq!(Some(1), stats.exceeding);
+
+        let (_, stats) = difference(&a, &a, &options).unwrap();
+        assert!(stats.psnr.is_infinite());
+
+        let c = DynamicImage::new_rgb8(3, 2);
+        assert_eq!(
+            "Cannot compare images of different sizes: 2x2 and 3x2",
+            difference(&a, &c, &options).unwrap_err().to_string()
+        );
+    }
+}
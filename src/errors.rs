@@ -0,0 +1,100 @@
+use image::error::LimitErrorKind;
+use image::ImageError;
+
+/// Why a file could not be turned into pixels.
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The image is larger than the configured maximum dimensions. Detected
+    /// from the header, before any pixel is allocated.
+    TooLarge {
+        width: u32,
+        height: u32,
+        max_width: u32,
+        max_height: u32,
+    },
+    /// Decoding would need more memory than the configured maximum.
+    TooMuchMemory {
+        max_alloc: u64,
+    },
+    Image(ImageError),
+    /// Decoding took longer than the configured time budget.
+    TimedOut {
+        after: std::time::Duration,
+    },
+    /// Too many in-process decodes that timed out are still running to start
+    /// another one.
+    Lingering {
+        count: usize,
+    },
+    /// The decoder crashed (panicked, or its sandbox process died), or the
+    /// sandbox process reported an error.
+    Worker(String),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::TooLarge {
+                width,
+                height,
+                max_width,
+                max_height,
+            } => write!(
+                f,
+                "image is {}x{}, larger than the allowed {}x{}",
+                width, height, max_width, max_height
+            ),
+            DecodeError::TooMuchMemory { max_alloc } => write!(
+                f,
+                "decoding would need more than the allowed {} MiB",
+                max_alloc / (1024 * 1024)
+            ),
+            DecodeError::Image(e) => e.fmt(f),
+            DecodeError::TimedOut { after } => {
+                write!(f, "decode timed out after {:.1}s", after.as_secs_f32())
+            }
+            DecodeError::Lingering { count } => write!(
+                f,
+                "{} decodes that timed out are still running, use --sandbox to have them stopped",
+                count
+            ),
+            DecodeError::Worker(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DecodeError::Image(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ImageError> for DecodeError {
+    fn from(e: ImageError) -> Self {
+        DecodeError::Image(e)
+    }
+}
+
+impl From<std::io::Error> for DecodeError {
+    fn from(e: std::io::Error) -> Self {
+        DecodeError::Image(ImageError::IoError(e))
+    }
+}
+
+impl DecodeError {
+    /// Turn the generic limit errors of the image crate into ours.
+    pub fn from_limits(e: ImageError, limits: &crate::fs_utils::DecodeLimits) -> Self {
+        match e {
+            ImageError::Limits(ref l) => match l.kind() {
+                LimitErrorKind::InsufficientMemory => DecodeError::TooMuchMemory {
+                    max_alloc: limits.max_alloc,
+                },
+                _ => DecodeError::Image(e),
+            },
+            e => DecodeError::Image(e),
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use image::imageops::FilterType;
+use image::{Rgba, RgbaImage};
+use show_image::glam::Vec2;
+use std::ops::Range;
+use std::sync::{Arc, RwLock};
+
+use crate::font;
+use crate::fs_utils::{suggested_items_to_cache, FileStatus, ImagePair};
+use crate::gallery::Gallery;
+
+/// Longest side of the thumbnails.
+const THUMB_SIZE: u32 = 64;
+const PADDING: u32 = 4;
+/// Height of the bar telling the load state under each thumbnail.
+const STATE_HEIGHT: u32 = 3;
+const SLOT_WIDTH: u32 = THUMB_SIZE + 2 * PADDING;
+const HEIGHT: u32 = THUMB_SIZE + 3 * PADDING + STATE_HEIGHT;
+/// Distance from the bottom of the visible part of the image.
+const MARGIN: f32 = 12.0;
+
+const BACKGROUND: Rgba<u8> = Rgba([0, 0, 0, 170]);
+const CURRENT: Rgba<u8> = Rgba([90, 140, 230, 255]);
+const EMPTY: Rgba<u8> = Rgba([60, 60, 60, 255]);
+const UNREAD: Rgba<u8> = Rgba([90, 90, 90, 255]);
+const LOADING: Rgba<u8> = Rgba([230, 190, 60, 255]);
+const READ: Rgba<u8> = Rgba([90, 190, 100, 255]);
+const FAILED: Rgba<u8> = Rgba([230, 90, 80, 255]);
+
+/// How far an image is from being shown.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LoadState {
+    Unread,
+    Loading,
+    /// Only the preview is decoded
+    Preview,
+    Read,
+    Failed,
+}
+
+impl LoadState {
+    fn color(&self) -> Rgba<u8> {
+        match self {
+            LoadState::Unread => UNREAD,
+            LoadState::Loading | LoadState::Preview => LOADING,
+            LoadState::Read => READ,
+            LoadState::Failed => FAILED,
+        }
+    }
+}
+
+/// Thumbnails of the images around the current one, the ones kept decoded,
+/// in a strip to put along the bottom of the window.
+pub struct Filmstrip {
+    cache: Arc<RwLock<Vec<FileStatus<ImagePair>>>>,
+    /// Images kept on each side of the current one.
+    side: usize,
+    /// Images in the strip last drawn.
+    range: Range<usize>,
+    /// What the strip last drawn showed, to only draw it again on changes.
+    drawn: Option<(usize, Vec<(LoadState, bool)>)>,
+}
+
+impl Filmstrip {
+    pub fn new(cache: Arc<RwLock<Vec<FileStatus<ImagePair>>>>, side: usize) -> Self {
+        Filmstrip {
+            cache,
+            side,
+            range: 0..0,
+            drawn: None,
+        }
+    }
+
+    /// The strip with image `current` highlighted, if it changed since it
+    /// was last drawn. Thumbnails come from `gallery`, asked only for
+    /// decoded images so that files are not read twice.
+    pub fn update(&mut self, current: usize, gallery: &Gallery) -> Option<RgbaImage> {
+        let len = self.cache.read().unwrap().len();
+        let range = suggested_items_to_cache(current, len, self.side);
+        let slots: Vec<(LoadState, Option<RgbaImage>)> = range
+            .clone()
+            .map(|index| {
+                let state = match &self.cache.read().unwrap()[index] {
+                    FileStatus::Unread => LoadState::Unread,
+                    FileStatus::Reading => LoadState::Loading,
+                    FileStatus::Thumbnail(_) => LoadState::Preview,
+                    FileStatus::Read(_) => LoadState::Read,
+                    FileStatus::Err(_) => LoadState::Failed,
+                };
+                let thumb = match state {
+                    LoadState::Preview | LoadState::Read => gallery.thumbnail(index),
+                    _ => None,
+                };
+                (state, thumb)
+            })
+            .collect();
+
+        let drawn = (
+            current,
+            slots
+                .iter()
+                .map(|(state, thumb)| (*state, thumb.is_some()))
+                .collect(),
+        );
+        if self.range == range && self.drawn.as_ref() == Some(&drawn) {
+            return None;
+        }
+        let strip = draw(&slots, current - range.start);
+        self.range = range;
+        self.drawn = Some(drawn);
+        Some(strip)
+    }
+
+    /// Draw the strip again on the next update, to show it anew.
+    pub fn invalidate(&mut self) {
+        self.drawn = None;
+    }
+
+    /// The image whose slot is at `point` in the strip last drawn.
+    pub fn index_at(&self, point: Vec2) -> Option<usize> {
+        if point.x < 0.0 || point.y < 0.0 || point.y >= HEIGHT as f32 {
+            return None;
+        }
+        let index = self.range.start + point.x as usize / SLOT_WIDTH as usize;
+        self.range.contains(&index).then_some(index)
+    }
+}
+
+/// Where a strip of `size` goes on screen: centered along the bottom of the
+/// part of the window from `min` to `max` that the image covers.
+pub fn origin(size: Vec2, (min, max): (Vec2, Vec2)) -> Vec2 {
+    let x = ((min.x + max.x - size.x) / 2.0).max(min.x);
+    Vec2::new(x, max.y - size.y - MARGIN)
+}
+
+fn draw(slots: &[(LoadState, Option<RgbaImage>)], current: usize) -> RgbaImage {
+    let mut strip = RgbaImage::from_pixel(SLOT_WIDTH * slots.len() as u32, HEIGHT, BACKGROUND);
+    for (n, (state, thumb)) in slots.iter().enumerate() {
+        let x = (n as u32 * SLOT_WIDTH) as i64;
+        if n == current {
+            // A frame, leaving the background see-through
+            font::fill_rect(&mut strip, x, 0, SLOT_WIDTH, 2, CURRENT);
+            font::fill_rect(&mut strip, x, HEIGHT as i64 - 2, SLOT_WIDTH, 2, CURRENT);
+            font::fill_rect(&mut strip, x, 2, 2, HEIGHT - 4, CURRENT);
+            font::fill_rect(
+                &mut strip,
+                x + SLOT_WIDTH as i64 - 2,
+                2,
+                2,
+                HEIGHT - 4,
+                CURRENT,
+            );
+        }
+
+        let (thumb_x, thumb_y) = (x + PADDING as i64, PADDING as i64);
+        match thumb {
+            Some(thumb) => {
+                let thumb = image::imageops::resize(
+                    thumb,
+                    (thumb.width() * THUMB_SIZE / thumb.height().max(thumb.width())).max(1),
+                    (thumb.height() * THUMB_SIZE / thumb.height().max(thumb.width())).max(1),
+                    FilterType::Triangle,
+                );
+                // Centered in its square
+                let left = thumb_x + (THUMB_SIZE - thumb.width()) as i64 / 2;
+                let top = thumb_y + (THUMB_SIZE - thumb.height()) as i64 / 2;
+                image::imageops::overlay(&mut strip, &thumb, left, top);
+            }
+            None => {
+                font::fill_rect(&mut strip, thumb_x, thumb_y, THUMB_SIZE, THUMB_SIZE, EMPTY);
+                if *state == LoadState::Failed {
+                    font::draw_text(&mut strip, thumb_x + 6, thumb_y + 6, "!", 3, FAILED);
+                }
+            }
+        }
+
+        let bar_y = thumb_y + (THUMB_SIZE + PADDING) as i64;
+        font::fill_rect(
+            &mut strip,
+            thumb_x,
+            bar_y,
+            THUMB_SIZE,
+            STATE_HEIGHT,
+            state.color(),
+        );
+    }
+    strip
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slots() {
+        let cache = Arc::new(RwLock::new(vec![FileStatus::Unread; 20]));
+        let mut filmstrip = Filmstrip::new(cache, 2);
+        filmstrip.range = 3..8;
+        let slot = |n: f32| Vec2::new((n + 0.5) * SLOT_WIDTH as f32, 10.0);
+        assert_eq!(Some(3), filmstrip.index_at(slot(0.0)));
+        assert_eq!(Some(7), filmstrip.index_at(slot(4.0)));
+        assert_eq!(None, filmstrip.index_at(slot(5.0)));
+        assert_eq!(None, filmstrip.index_at(Vec2::new(10.0, HEIGHT as f32)));
+
+        // Centered under the image, unless wider than it
+        let strip = Vec2::new(100.0, 50.0);
+        let covered = (Vec2::new(50.0, 0.0), Vec2::new(350.0, 300.0));
+        assert_eq!(Vec2::new(150.0, 238.0), origin(strip, covered));
+        assert_eq!(50.0, origin(strip * 4.0, covered).x);
+    }
+}
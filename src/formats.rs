@@ -0,0 +1,133 @@
+use image::error::{DecodingError, ImageFormatHint};
+use image::{DynamicImage, ImageError, ImageFormat};
+use std::path::Path;
+
+use crate::errors::DecodeError;
+use crate::fs_utils::{load_with_limits, DecodeLimits};
+
+/// Formats whose decoders are optional, enabled through cargo features.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OptionalFormat {
+    Avif,
+    Heif,
+    Jxl,
+}
+
+impl OptionalFormat {
+    pub fn from_path(path: impl AsRef<Path>) -> Option<OptionalFormat> {
+        let ext = path.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "avif" => Some(OptionalFormat::Avif),
+            "heic" | "heif" | "hif" => Some(OptionalFormat::Heif),
+            "jxl" => Some(OptionalFormat::Jxl),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            OptionalFormat::Avif => "AVIF",
+            OptionalFormat::Heif => "HEIF",
+            OptionalFormat::Jxl => "JPEG XL",
+        }
+    }
+
+    /// Name of the cargo feature providing the decoder.
+    pub fn feature(&self) -> &'static str {
+        match self {
+            OptionalFormat::Avif => "avif",
+            OptionalFormat::Heif => "heif",
+            OptionalFormat::Jxl => "jxl",
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        match self {
+            OptionalFormat::Avif => cfg!(feature = "avif"),
+            OptionalFormat::Heif => cfg!(feature = "heif"),
+            OptionalFormat::Jxl => cfg!(feature = "jxl"),
+        }
+    }
+
+    fn error(&self, e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> ImageError {
+        ImageError::Decoding(DecodingError::new(
+            ImageFormatHint::Name(self.name().to_string()),
+            e,
+        ))
+    }
+
+    /// Decode `data`, a file in this format.
+    pub fn decode(&self, data: &[u8], limits: &DecodeLimits) -> Result<DynamicImage, DecodeError> {
+        if !self.is_enabled() {
+            return Err(DecodeError::Image(self.error(format!(
+                "{} files are supported, but the `{}` feature is disabled in this build",
+                self.name(),
+                self.feature()
+            ))));
+        }
+
+        match self {
+            // With the feature on, the image crate decodes it (through dav1d)
+            OptionalFormat::Avif => load_with_limits(data, Some(ImageFormat::Avif), limits),
+            OptionalFormat::Heif => self.decode_heif(data, limits),
+            OptionalFormat::Jxl => self.decode_jxl(data, limits),
+        }
+    }
+
+    #[cfg(feature = "heif")]
+    fn decode_heif(&self, data: &[u8], limits: &DecodeLimits) -> Result<DynamicImage, DecodeError> {
+        use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+        let lib_heif = LibHeif::new();
+        let ctx = HeifContext::read_from_bytes(data).map_err(|e| self.error(e))?;
+        let handle = ctx.primary_image_handle().map_err(|e| self.error(e))?;
+        limits.check(handle.width(), handle.height())?;
+        limits.check_alloc(u64::from(handle.width()) * u64::from(handle.height()) * 4)?;
+        // libheif applies the orientation (irot/imir) while decoding
+        let image = lib_heif
+            .decode(&handle, ColorSpace::Rgb(RgbChroma::Rgba), None)
+            .map_err(|e| self.error(e))?;
+
+        let (w, h) = (image.width(), image.height());
+        let plane = image
+            .planes()
+            .interleaved
+            .ok_or_else(|| self.error("no interleaved plane"))?;
+
+        // Rows may be padded, copy them one by one
+        let mut buffer = Vec::with_capacity(w as usize * h as usize * 4);
+        for row in plane.data.chunks(plane.stride).take(h as usize) {
+            buffer.extend_from_slice(&row[..w as usize * 4]);
+        }
+
+        image::RgbaImage::from_raw(w, h, buffer)
+            .map(DynamicImage::ImageRgba8)
+            .ok_or_else(|| self.error("unexpected buffer size").into())
+    }
+
+    #[cfg(not(feature = "heif"))]
+    fn decode_heif(
+        &self,
+        _data: &[u8],
+        _limits: &DecodeLimits,
+    ) -> Result<DynamicImage, DecodeError> {
+        unreachable!("checked by is_enabled")
+    }
+
+    #[cfg(feature = "jxl")]
+    fn decode_jxl(&self, data: &[u8], limits: &DecodeLimits) -> Result<DynamicImage, DecodeError> {
+        use jxl_oxide::integration::JxlDecoder;
+
+        let decoder = JxlDecoder::new(std::io::Cursor::new(data)).map_err(|e| self.error(e))?;
+        crate::fs_utils::decode_with_limits(decoder, limits)
+    }
+
+    #[cfg(not(feature = "jxl"))]
+    fn decode_jxl(
+        &self,
+        _data: &[u8],
+        _limits: &DecodeLimits,
+    ) -> Result<DynamicImage, DecodeError> {
+        unreachable!("checked by is_enabled")
+    }
+}
@@ -1,14 +1,40 @@
-use image::{DynamicImage, ImageError};
+use image::{DynamicImage, ImageDecoder, ImageError};
 use log::{debug, error};
 use std::fs::File;
 use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use std::sync::mpsc::{RecvTimeoutError, TryRecvError};
 use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use threadpool::ThreadPool;
 
+use crate::background::Background;
+use crate::errors::DecodeError;
+use crate::formats::OptionalFormat;
+use crate::placeholder;
+use crate::raw;
+use crate::sandbox;
+use crate::subimages;
+use crate::svg;
+
+/// What we know about an image besides its pixels.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImageDetails {
+    /// Sensor size of camera RAW files, of which we may only show a preview.
+    pub sensor_size: Option<(u32, u32)>,
+    /// Which of the pages (icon sizes, texture mips...) of the file this is.
+    pub subimage: usize,
+    /// How many of them are in the file, 0 or 1 for single image files.
+    pub subimages: usize,
+    /// Pixels as decoded, before any downscaling for display.
+    pub size: (u32, u32),
+    /// Whether the pixels are a smaller preview, the full image to follow.
+    pub thumbnail: bool,
+}
+
 #[derive(Clone, PartialEq)]
-pub struct ImagePair(pub PathBuf, pub Option<DynamicImage>);
+pub struct ImagePair(pub PathBuf, pub Option<DynamicImage>, pub ImageDetails);
 
 impl ImagePair {
     pub fn path_str(&self) -> std::borrow::Cow<'_, str> {
@@ -24,6 +50,249 @@ impl ImagePair {
     }
 }
 
+/// Bounds on what we accept to decode, so that a decompression bomb is
+/// rejected instead of exhausting the memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecodeLimits {
+    pub max_width: u32,
+    pub max_height: u32,
+    /// Bytes a decoder may allocate.
+    pub max_alloc: u64,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            max_width: 65_535,
+            max_height: 65_535,
+            max_alloc: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+impl DecodeLimits {
+    pub fn check(&self, width: u32, height: u32) -> std::result::Result<(), DecodeError> {
+        if width > self.max_width || height > self.max_height {
+            return Err(DecodeError::TooLarge {
+                width,
+                height,
+                max_width: self.max_width,
+                max_height: self.max_height,
+            });
+        }
+        Ok(())
+    }
+
+    /// Refuse decodes that would allocate more than `max_alloc` bytes.
+    pub fn check_alloc(&self, bytes: u64) -> std::result::Result<(), DecodeError> {
+        if bytes > self.max_alloc {
+            return Err(DecodeError::TooMuchMemory {
+                max_alloc: self.max_alloc,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn image_limits(&self) -> image::Limits {
+        let mut limits = image::Limits::default();
+        limits.max_image_width = Some(self.max_width);
+        limits.max_image_height = Some(self.max_height);
+        limits.max_alloc = Some(self.max_alloc);
+        limits
+    }
+}
+
+/// Settings used when turning a file into pixels.
+#[derive(Clone, Debug)]
+pub struct LoadOptions {
+    /// Size vector images (SVG) are rasterized to fit in.
+    pub svg_size: (u32, u32),
+    pub limits: DecodeLimits,
+    /// Decode in a restricted child process (see `sandbox`).
+    pub sandbox: bool,
+    /// Give up on decodes taking longer than this.
+    pub timeout: Option<Duration>,
+    /// Files that fail to decode are skipped rather than shown as a
+    /// placeholder.
+    pub skip_broken: bool,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        LoadOptions {
+            svg_size: (1024, 1024),
+            limits: DecodeLimits::default(),
+            sandbox: false,
+            timeout: Some(Duration::from_secs(30)),
+            skip_broken: false,
+        }
+    }
+}
+
+/// Decode through the image crate, checking the dimensions against `limits`
+/// before any pixel buffer is allocated.
+pub fn decode_with_limits(
+    mut decoder: impl ImageDecoder,
+    limits: &DecodeLimits,
+) -> std::result::Result<DynamicImage, DecodeError> {
+    let (width, height) = decoder.dimensions();
+    limits.check(width, height)?;
+    limits.check_alloc(decoder.total_bytes())?;
+    decoder
+        .set_limits(limits.image_limits())
+        .and_then(|_| DynamicImage::from_decoder(decoder))
+        .map_err(|e| DecodeError::from_limits(e, limits))
+}
+
+/// Like `image::load_from_memory`, with limits. `format` is what the file
+/// name tells, the contents win when they are recognized.
+pub fn load_with_limits(
+    data: &[u8],
+    format: Option<image::ImageFormat>,
+    limits: &DecodeLimits,
+) -> std::result::Result<DynamicImage, DecodeError> {
+    let mut reader = image::ImageReader::new(std::io::Cursor::new(data));
+    if let Some(format) = format {
+        reader.set_format(format);
+    }
+    let decoder = reader.with_guessed_format()?.into_decoder()?;
+    decode_with_limits(decoder, limits)
+}
+
+/// Whether we know how to open the file, judging by its name.
+pub fn is_supported_image(path: impl AsRef<Path>) -> bool {
+    let path = path.as_ref();
+    svg::is_svg(path)
+        || raw::is_raw(path)
+        || OptionalFormat::from_path(path).is_some()
+        || image::ImageFormat::from_path(path).is_ok()
+}
+
+/// In-process decodes that timed out but are still running, keeping a core
+/// busy, at most.
+const MAX_LINGERING_DECODES: usize = 2;
+
+static LINGERING_DECODES: AtomicUsize = AtomicUsize::new(0);
+
+pub type DecodeResult = std::result::Result<(DynamicImage, ImageDetails), DecodeError>;
+
+/// Decode the image at `path`, whatever its format.
+pub fn open_image(path: impl AsRef<Path>, options: &LoadOptions) -> DecodeResult {
+    open_subimage(path, 0, options)
+}
+
+/// Decode one of the subimages of the file at `path` (see `subimages`).
+pub fn open_subimage(path: impl AsRef<Path>, index: usize, options: &LoadOptions) -> DecodeResult {
+    let path = path.as_ref();
+    let decoded = match options.timeout {
+        _ if options.sandbox => sandbox::decode_in_child(path, index, options),
+        Some(timeout) => decode_with_timeout(path, index, options, timeout),
+        None => decode_file(path, index, options),
+    };
+    decoded.map(|(image, details)| {
+        let size = (image.width(), image.height());
+        (image, ImageDetails { size, ..details })
+    })
+}
+
+/// Decode in a thread of its own, so that we can stop waiting for it. The
+/// thread can't be stopped though: after a timeout it runs on in the
+/// background until the decoder is done, and its result is dropped. At most
+/// `MAX_LINGERING_DECODES` of them may run at once, later decodes are refused.
+fn decode_with_timeout(
+    path: &Path,
+    index: usize,
+    options: &LoadOptions,
+    timeout: Duration,
+) -> DecodeResult {
+    let lingering = LINGERING_DECODES.load(Ordering::SeqCst);
+    if lingering >= MAX_LINGERING_DECODES {
+        return Err(DecodeError::Lingering { count: lingering });
+    }
+
+    const RUNNING: u8 = 0;
+    const DONE: u8 = 1;
+    const ABANDONED: u8 = 2;
+    let state = Arc::new(AtomicU8::new(RUNNING));
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let path = path.to_owned();
+    let options = options.clone();
+    let thread_state = state.clone();
+    std::thread::Builder::new()
+        .name("decode".to_string())
+        .spawn(move || {
+            let _ = tx.send(decode_file(&path, index, &options));
+            if thread_state.swap(DONE, Ordering::SeqCst) == ABANDONED {
+                LINGERING_DECODES.fetch_sub(1, Ordering::SeqCst);
+            }
+        })?;
+
+    match rx.recv_timeout(timeout) {
+        Ok(res) => res,
+        Err(RecvTimeoutError::Timeout) => {
+            // Counted first, so that the thread can't uncount it before
+            LINGERING_DECODES.fetch_add(1, Ordering::SeqCst);
+            if state.swap(ABANDONED, Ordering::SeqCst) == DONE {
+                LINGERING_DECODES.fetch_sub(1, Ordering::SeqCst);
+            }
+            Err(DecodeError::TimedOut { after: timeout })
+        }
+        Err(RecvTimeoutError::Disconnected) => {
+            Err(DecodeError::Worker("decoder panicked".to_string()))
+        }
+    }
+}
+
+/// Decode in this process, what `open_subimage` does when not sandboxed.
+pub fn decode_file(path: &Path, index: usize, options: &LoadOptions) -> DecodeResult {
+    decode_data(path, &read_image(path)?, index, options)
+}
+
+/// Decode `data`, the contents of the file at `path`. The name tells the
+/// format, and where to look for what the file refers to (SVG resources,
+/// RAW sensor data).
+pub fn decode_data(path: &Path, data: &[u8], index: usize, options: &LoadOptions) -> DecodeResult {
+    let image = if svg::is_svg(path) {
+        let (w, h) = options.svg_size;
+        svg::rasterize(path, data, w, h)?
+    } else if let Some(format) = OptionalFormat::from_path(path) {
+        format.decode(data, &options.limits)?
+    } else if raw::is_raw(path) {
+        let raw = raw::decode(path, data, &options.limits)?;
+        let details = ImageDetails {
+            sensor_size: raw.sensor_size,
+            ..Default::default()
+        };
+        return Ok((raw.image, details));
+    } else {
+        let (image, subimages) = subimages::decode(path, data, index, &options.limits)?;
+        let details = ImageDetails {
+            subimage: index,
+            subimages,
+            ..Default::default()
+        };
+        return Ok((image, details));
+    };
+    Ok((image, ImageDetails::default()))
+}
+
+/// Load a subimage ready to be displayed, e.g. to show another page of the
+/// current file.
+pub fn load_subimage(
+    path: impl AsRef<Path>,
+    index: usize,
+    options: &LoadOptions,
+) -> std::result::Result<ImagePair, DecodeError> {
+    let path = path.as_ref();
+    let (image, details) = open_subimage(path, index, options)?;
+    Ok(ImagePair(
+        path.to_owned(),
+        Some(clamp_image_size(image)),
+        details,
+    ))
+}
+
 pub fn read_image(path: impl AsRef<Path>) -> std::io::Result<Vec<u8>> {
     let mut buffer = Vec::new();
     {
@@ -34,7 +303,7 @@ pub fn read_image(path: impl AsRef<Path>) -> std::io::Result<Vec<u8>> {
     Ok(buffer)
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Direction {
     Left,
     Right,
@@ -70,10 +339,10 @@ impl From<std::result::Result<DynamicImage, ImageError>> for FileStatus<DynamicI
     }
 }
 
-impl From<(PathBuf, std::result::Result<DynamicImage, ImageError>)> for FileStatus<ImagePair> {
-    fn from((p, res): (PathBuf, std::result::Result<DynamicImage, ImageError>)) -> Self {
+impl From<(PathBuf, DecodeResult)> for FileStatus<ImagePair> {
+    fn from((p, res): (PathBuf, DecodeResult)) -> Self {
         match res {
-            Ok(x) => FileStatus::Read(ImagePair(p, Some(clamp_image_size(x)))),
+            Ok((x, details)) => FileStatus::Read(ImagePair(p, Some(clamp_image_size(x)), details)),
             Err(x) => FileStatus::Err(x.to_string()),
         }
     }
@@ -81,7 +350,7 @@ impl From<(PathBuf, std::result::Result<DynamicImage, ImageError>)> for FileStat
 
 /// Generate a small thumbnail for fast preview display.
 /// Returns None if the image is already small enough to serve as its own thumbnail.
-fn generate_thumbnail(img: &DynamicImage, max_dim: u32) -> Option<DynamicImage> {
+pub fn generate_thumbnail(img: &DynamicImage, max_dim: u32) -> Option<DynamicImage> {
     let (w, h) = (img.width(), img.height());
     if w <= max_dim && h <= max_dim {
         return None;
@@ -120,37 +389,95 @@ impl<T, E> FileStatus<T, E> {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn start_file_reader(
     cache: Arc<RwLock<Vec<FileStatus<ImagePair>>>>,
     paths: Vec<PathBuf>,
     start_idx: usize,
     cache_side_max_length: usize,
+    options: LoadOptions,
+    pool: ThreadPool,
     rx: std::sync::mpsc::Receiver<Option<usize>>,
-    tx: std::sync::mpsc::Sender<Result<Option<usize>, String>>,
+    tx: std::sync::mpsc::Sender<Result<Option<usize>, (usize, String)>>,
     //wakeup: impl Fn() -> (),
     w: show_image::WindowProxy,
+    background: Arc<RwLock<Background>>,
+    shows: Arc<AtomicBool>,
 ) {
     // TODO let's start by storing every loaded image, we'll later find a way
     // to drop some of them
 
-    let n_workers = 4;
-    let pool = ThreadPool::new(n_workers);
-
     debug!("start_file_reader");
 
-    // immediately load the first image
-    {
-        let maybe_image = image::open(&paths[start_idx]);
-        let mut c = cache.write().unwrap();
-        c[start_idx] = FileStatus::from((paths[start_idx].clone(), maybe_image));
-    }
+    // The image asked for last, the others are only decoded ahead around it
+    let current = Arc::new(AtomicUsize::new(start_idx));
+
+    // Decode an image on the pool unless it was already. The one to show
+    // gets a preview first if it is large. Preloads that aren't around the
+    // current image anymore by the time a worker is free are dropped.
+    let load = |idx: usize, preview: bool| {
+        let c = cache.clone();
+        let path = paths[idx].clone();
+        let options = options.clone();
+        let current = current.clone();
+        let len = paths.len();
+        pool.execute(move || {
+            {
+                let mut rw_lock = c.write().unwrap();
+                let around = suggested_items_to_cache(
+                    current.load(Ordering::Relaxed),
+                    len,
+                    cache_side_max_length,
+                );
+                if rw_lock[idx] != FileStatus::Unread || !(preview || around.contains(&idx)) {
+                    return;
+                }
+                rw_lock[idx] = FileStatus::Reading;
+            }
+
+            let status = match open_image(&path, &options) {
+                Ok((img, details)) => {
+                    const THUMB_MAX: u32 = 512;
+                    if let Some(thumb) = generate_thumbnail(&img, THUMB_MAX).filter(|_| preview) {
+                        c.write().unwrap()[idx] = FileStatus::Thumbnail(ImagePair(
+                            path.clone(),
+                            Some(thumb),
+                            ImageDetails {
+                                thumbnail: true,
+                                ..details.clone()
+                            },
+                        ));
+                    }
+                    FileStatus::Read(ImagePair(path, Some(clamp_image_size(img)), details))
+                }
+                Err(e) => FileStatus::Err(e.to_string()),
+            };
+            c.write().unwrap()[idx] = status;
+        });
+    };
+
+    // Start on the first image before it is even asked for
+    load(start_idx, true);
+
+    // Images go straight to the window, over the background it would give
+    // them, unless it shows something else for now
+    let show = |image: DynamicImage| {
+        if !shows.load(Ordering::Relaxed) {
+            return;
+        }
+        let window = w
+            .run_function_wait(|window_handle| window_handle.inner_size().as_vec2())
+            .unwrap_or_default();
+        let image = background.read().unwrap().apply(image, window);
+        let _ = w.set_image("", image);
+    };
 
     let mut idx: usize;
     let mut pending_idx: Option<usize> = None;
 
     'outer: loop {
         // Get latest idx. Use pending_idx if we have one from a previous
-        // iteration (user moved on while we were showing a thumbnail).
+        // iteration (user moved on while we were waiting for an image).
         if let Some(pending) = pending_idx.take() {
             idx = pending;
         } else {
@@ -174,136 +501,56 @@ pub fn start_file_reader(
         }
 
         debug!("Got a request to load idx {}", idx);
-
-        // Helper: decode an image, store thumbnail first, then full quality.
-        // Returns the decoded image for display.
-        let display_image = |idx: usize, cache: &Arc<RwLock<Vec<FileStatus<ImagePair>>>>| {
-            let status = cache.read().unwrap()[idx].clone();
-            match status {
-                FileStatus::Read(ref k) | FileStatus::Thumbnail(ref k) => {
-                    Some(k.image_clone().unwrap())
+        current.store(idx, Ordering::Relaxed);
+        load(idx, true);
+
+        // Show what the cache has as it gets better (the preview of a large
+        // image, then the image) while still reading requests, so that the
+        // user can move on from a slow file (the pool gives up on its own
+        // once the decode times out).
+        let mut previewed = false;
+        loop {
+            let ready = match &cache.read().unwrap()[idx] {
+                FileStatus::Read(k) => Some(Ok((k.image_clone().unwrap(), true))),
+                FileStatus::Thumbnail(k) if !previewed => {
+                    Some(Ok((k.image_clone().unwrap(), false)))
                 }
-                FileStatus::Err(_) => None,
-                FileStatus::Reading => {
-                    // Wait for pool to finish
-                    loop {
-                        let s = cache.read().unwrap()[idx].clone();
-                        match s {
-                            FileStatus::Reading => {
-                                std::thread::sleep(Duration::from_millis(10));
-                            }
-                            FileStatus::Read(k) | FileStatus::Thumbnail(k) => {
-                                return Some(k.image_clone().unwrap());
-                            }
-                            _ => return None,
-                        }
+                FileStatus::Err(e) => Some(Err(e.clone())),
+                _ => None,
+            };
+            match ready {
+                Some(Ok((image, done))) => {
+                    show(image);
+                    tx.send(Ok(Some(idx))).unwrap();
+                    if done {
+                        break;
                     }
+                    previewed = true;
                 }
-                FileStatus::Unread => None,
-            }
-        };
-
-        // Try to display from cache first
-        if let Some(img) = display_image(idx, &cache) {
-            let _ = w.set_image("", img);
-            tx.send(Ok(Some(idx))).unwrap();
-        } else {
-            // Not cached at all — decode now
-            debug!(
-                "Image not cached, loading {}: {}",
-                idx,
-                &paths[idx].to_string_lossy()
-            );
-            cache.write().unwrap()[idx] = FileStatus::Reading;
-            match image::open(&paths[idx]) {
-                Ok(img) => {
-                    // For large images, show a fast thumbnail first
-                    const THUMB_MAX: u32 = 512;
-                    if let Some(thumb) = generate_thumbnail(&img, THUMB_MAX) {
-                        cache.write().unwrap()[idx] = FileStatus::Thumbnail(ImagePair(
-                            paths[idx].clone(),
-                            Some(thumb.clone()),
-                        ));
-                        let _ = w.set_image("", thumb);
-                        tx.send(Ok(Some(idx))).unwrap();
-
-                        // Before the expensive Lanczos3 clamp, check if user moved on
-                        if let Ok(msg) = rx.try_recv() {
-                            // Offload full-quality processing to pool
-                            let c = cache.clone();
-                            let p = paths[idx].clone();
-                            let captured_idx = idx;
-                            pool.execute(move || {
-                                let clamped = clamp_image_size(img);
-                                c.write().unwrap()[captured_idx] =
-                                    FileStatus::Read(ImagePair(p, Some(clamped)));
-                            });
-
-                            match msg {
-                                Some(next_idx) => {
-                                    pending_idx = Some(next_idx);
-                                    continue 'outer;
-                                }
-                                None => break 'outer,
-                            }
-                        }
+                // No point in trying again
+                Some(Err(e)) => {
+                    if !options.skip_broken {
+                        show(placeholder::error_image(&paths[idx], &e));
                     }
-
-                    // Small image or user stayed — do full clamp and display
-                    let clamped = clamp_image_size(img);
-                    cache.write().unwrap()[idx] =
-                        FileStatus::Read(ImagePair(paths[idx].clone(), Some(clamped.clone())));
-                    let _ = w.set_image("", clamped);
-                    tx.send(Ok(Some(idx))).unwrap();
-                }
-                Err(e) => {
-                    cache.write().unwrap()[idx] = FileStatus::Err(e.to_string());
-                    tx.send(Err(e.to_string())).unwrap();
+                    tx.send(Err((idx, e))).unwrap();
+                    break;
                 }
+                None => {}
             }
-        }
-
-        // If the displayed image was only a thumbnail, upgrade to full quality
-        if matches!(cache.read().unwrap()[idx], FileStatus::Thumbnail(_)) {
-            match image::open(&paths[idx]) {
-                Ok(img) => {
-                    let clamped = clamp_image_size(img);
-                    cache.write().unwrap()[idx] =
-                        FileStatus::Read(ImagePair(paths[idx].clone(), Some(clamped.clone())));
-                    let _ = w.set_image("", clamped);
-                    tx.send(Ok(Some(idx))).unwrap();
-                }
-                Err(e) => {
-                    cache.write().unwrap()[idx] = FileStatus::Err(e.to_string());
+            match rx.try_recv() {
+                Ok(Some(next_idx)) => {
+                    pending_idx = Some(next_idx);
+                    continue 'outer;
                 }
+                Ok(None) | Err(TryRecvError::Disconnected) => break 'outer,
+                Err(TryRecvError::Empty) => std::thread::sleep(Duration::from_millis(10)),
             }
         }
 
-        let tmp_range = suggested_items_to_cache(idx, paths.len(), cache_side_max_length);
-
-        for some_idx in tmp_range {
-            let c = cache.clone();
-
-            if c.read().unwrap()[some_idx] == FileStatus::Unread {
-                let f_path = paths[some_idx].clone();
-
-                debug!("preload img {:?}", f_path);
-
-                pool.execute(move || {
-                    {
-                        let mut rw_lock = c.write().unwrap();
-                        let c_rw = &mut rw_lock[some_idx];
-
-                        if *c_rw != FileStatus::Unread {
-                            return;
-                        }
-
-                        *c_rw = FileStatus::Reading;
-                    }
-
-                    let maybe_image = image::open(&f_path);
-                    c.write().unwrap()[some_idx] = FileStatus::from((f_path.clone(), maybe_image));
-                });
+        for some_idx in suggested_items_to_cache(idx, paths.len(), cache_side_max_length) {
+            if cache.read().unwrap()[some_idx] == FileStatus::Unread {
+                debug!("preload img {:?}", paths[some_idx]);
+                load(some_idx, false);
             }
         }
     }
@@ -352,4 +599,38 @@ mod tests {
         assert_eq!(3..14, suggested_items_to_cache(9, 14, 5));
         assert_eq!(0..10, suggested_items_to_cache(0, 10, 5));
     }
+
+    #[test]
+    fn test_decode_with_limits() {
+        let mut png = Vec::new();
+        DynamicImage::new_rgb8(100, 40)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let decoder = || image::codecs::png::PngDecoder::new(std::io::Cursor::new(&png)).unwrap();
+
+        let limits = DecodeLimits {
+            max_width: 50,
+            ..Default::default()
+        };
+        assert!(matches!(
+            decode_with_limits(decoder(), &limits),
+            Err(DecodeError::TooLarge {
+                width: 100,
+                height: 40,
+                ..
+            })
+        ));
+
+        let limits = DecodeLimits {
+            max_alloc: 1000,
+            ..Default::default()
+        };
+        assert!(matches!(
+            decode_with_limits(decoder(), &limits),
+            Err(DecodeError::TooMuchMemory { max_alloc: 1000 })
+        ));
+
+        let image = decode_with_limits(decoder(), &DecodeLimits::default()).unwrap();
+        assert_eq!((100, 40), (image.width(), image.height()));
+    }
 }
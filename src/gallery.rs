@@ -0,0 +1,550 @@
+use image::{DynamicImage, Rgba, RgbaImage};
+use show_image::glam::{Affine2, UVec2, Vec2};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use threadpool::ThreadPool;
+
+use crate::background::Background;
+use crate::font;
+use crate::fs_utils::{
+    generate_thumbnail, open_image, suggested_items_to_cache, FileStatus, ImagePair, LoadOptions,
+};
+
+/// Longest side of the thumbnails.
+pub const THUMB_SIZE: u32 = 160;
+const PADDING: u32 = 8;
+const LABEL_HEIGHT: u32 = 13;
+const CELL_WIDTH: u32 = THUMB_SIZE + 2 * PADDING;
+const CELL_HEIGHT: u32 = THUMB_SIZE + 2 * PADDING + LABEL_HEIGHT;
+/// Two clicks on a thumbnail within this open it.
+const DOUBLE_CLICK: Duration = Duration::from_millis(400);
+
+const BACKGROUND: Rgba<u8> = Rgba([32, 32, 32, 255]);
+const SELECTION: Rgba<u8> = Rgba([90, 140, 230, 255]);
+const PENDING: Rgba<u8> = Rgba([60, 60, 60, 255]);
+const FAILED: Rgba<u8> = Rgba([230, 90, 80, 255]);
+const TEXT: Rgba<u8> = Rgba([200, 200, 200, 255]);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GalleryMove {
+    Left,
+    Right,
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    First,
+    Last,
+}
+
+enum Thumb {
+    Missing,
+    Pending,
+    Ready(RgbaImage),
+    Failed,
+}
+
+/// Where the gallery is, independently of any window.
+struct GalleryState {
+    len: usize,
+    selected: usize,
+    /// Row shown at the top.
+    first_row: usize,
+    /// Rows scrolled by less than one, kept until they add up to one.
+    scrolled: f32,
+    /// Size of the window, in pixels.
+    size: UVec2,
+}
+
+impl GalleryState {
+    fn columns(&self) -> usize {
+        (self.size.x / CELL_WIDTH).max(1) as usize
+    }
+
+    /// Rows fully visible.
+    fn rows(&self) -> usize {
+        (self.size.y / CELL_HEIGHT).max(1) as usize
+    }
+
+    /// Indices of the images with a cell in the window, even partly.
+    fn visible(&self) -> std::ops::Range<usize> {
+        let start = self.first_row * self.columns();
+        let rows = self.size.y.div_ceil(CELL_HEIGHT).max(1) as usize;
+        start.min(self.len)..(start + rows * self.columns()).min(self.len)
+    }
+
+    fn move_selection(&mut self, direction: GalleryMove) {
+        let columns = self.columns();
+        let page = columns * self.rows();
+        let last = self.len.saturating_sub(1);
+        self.selected = match direction {
+            GalleryMove::Left => self.selected.saturating_sub(1),
+            GalleryMove::Right => (self.selected + 1).min(last),
+            GalleryMove::Up if self.selected >= columns => self.selected - columns,
+            GalleryMove::Down if self.selected + columns <= last => self.selected + columns,
+            GalleryMove::Up | GalleryMove::Down => self.selected,
+            GalleryMove::PageUp => self.selected.saturating_sub(page),
+            GalleryMove::PageDown => (self.selected + page).min(last),
+            GalleryMove::First => 0,
+            GalleryMove::Last => last,
+        };
+        self.scroll_to_selection();
+    }
+
+    /// Scroll as little as possible for the selection to be fully visible.
+    fn scroll_to_selection(&mut self) {
+        let row = self.selected / self.columns();
+        if row < self.first_row {
+            self.first_row = row;
+        } else if row >= self.first_row + self.rows() {
+            self.first_row = row + 1 - self.rows();
+        }
+    }
+
+    /// Scroll by `rows`, up if negative, keeping the last row in view.
+    /// Fractions of rows, e.g. from touchpads, add up over calls.
+    fn scroll(&mut self, rows: f32) {
+        self.scrolled += rows;
+        let whole = self.scrolled.trunc();
+        self.scrolled -= whole;
+        let last_row = self.len.saturating_sub(1) / self.columns();
+        let max_first = last_row.saturating_sub(self.rows() - 1);
+        self.first_row = self
+            .first_row
+            .saturating_add_signed(whole as isize)
+            .min(max_first);
+    }
+
+    /// The image whose cell is at `position` (window pixels).
+    fn index_at(&self, position: Vec2) -> Option<usize> {
+        if position.x < 0.0 || position.y < 0.0 {
+            return None;
+        }
+        let column = position.x as usize / CELL_WIDTH as usize;
+        let row = self.first_row + position.y as usize / CELL_HEIGHT as usize;
+        let index = row * self.columns() + column;
+        (column < self.columns() && index < self.len).then_some(index)
+    }
+}
+
+struct Shared {
+    state: GalleryState,
+    thumbs: Vec<Thumb>,
+    open: bool,
+    /// Image shown when the gallery was opened, to go back to.
+    opened_from: usize,
+    /// Whether the file reader showed images before the gallery was opened.
+    reader_showed: bool,
+}
+
+/// A grid of thumbnails of all the images, drawn in place of the image.
+///
+/// Thumbnails are made in the background for the cells in view, from the
+/// decoded image if it is cached. Images decoded for them around the
+/// selection go to the cache, ready to be opened; others are decoded as
+/// small as their format allows. As each one is ready, the grid is drawn
+/// again right away, so that it shows without waiting for an event.
+pub struct Gallery {
+    shared: Arc<Mutex<Shared>>,
+    window: show_image::WindowProxy,
+    paths: Arc<Vec<PathBuf>>,
+    cache: Arc<RwLock<Vec<FileStatus<ImagePair>>>>,
+    /// Images cached on each side of the selection, as the file reader does
+    /// around the current one.
+    cache_side: usize,
+    options: LoadOptions,
+    /// For images that are only made thumbnails of.
+    thumb_options: LoadOptions,
+    background: Arc<RwLock<Background>>,
+    /// Shared with the file reader, not to decode more files at once.
+    pool: ThreadPool,
+    last_click: Option<(Instant, usize)>,
+    /// Whether the file reader puts the images it reads on screen, which it
+    /// mustn't over the gallery.
+    reader_shows: Arc<AtomicBool>,
+}
+
+impl Gallery {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        window: show_image::WindowProxy,
+        paths: Vec<PathBuf>,
+        cache: Arc<RwLock<Vec<FileStatus<ImagePair>>>>,
+        cache_side: usize,
+        pool: ThreadPool,
+        options: LoadOptions,
+        background: Arc<RwLock<Background>>,
+        reader_shows: Arc<AtomicBool>,
+    ) -> Self {
+        let shared = Shared {
+            state: GalleryState {
+                len: paths.len(),
+                selected: 0,
+                first_row: 0,
+                scrolled: 0.0,
+                size: UVec2::ONE,
+            },
+            thumbs: paths.iter().map(|_| Thumb::Missing).collect(),
+            open: false,
+            opened_from: 0,
+            reader_showed: true,
+        };
+        Gallery {
+            shared: Arc::new(Mutex::new(shared)),
+            window,
+            paths: Arc::new(paths),
+            cache,
+            cache_side,
+            thumb_options: LoadOptions {
+                svg_size: (THUMB_SIZE, THUMB_SIZE),
+                ..options.clone()
+            },
+            options,
+            background,
+            pool,
+            last_click: None,
+            reader_shows,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.shared.lock().unwrap().open
+    }
+
+    /// Show the gallery in a window of `size`, with image `selected`
+    /// selected.
+    pub fn open(&self, selected: usize, size: UVec2) {
+        {
+            let mut shared = self.shared.lock().unwrap();
+            shared.open = true;
+            shared.opened_from = selected;
+            shared.reader_showed = self.reader_shows.swap(false, Ordering::Relaxed);
+            shared.state.scrolled = 0.0;
+            shared.state.size = size.max(UVec2::ONE);
+            shared.state.selected = selected.min(shared.state.len.saturating_sub(1));
+            shared.state.scroll_to_selection();
+        }
+        let title = format!("Gallery: {} images", self.paths.len());
+        let _ = self.window.run_function_wait(move |mut window_handle| {
+            window_handle.set_title(&title);
+            window_handle.set_overlays_visible(false);
+        });
+        self.refresh();
+    }
+
+    /// Go back to single images, returning the one selected.
+    pub fn close(&self) -> usize {
+        self.leave().state.selected
+    }
+
+    /// Go back to single images, returning the one shown before the
+    /// gallery was opened.
+    pub fn cancel(&self) -> usize {
+        self.leave().opened_from
+    }
+
+    fn leave(&self) -> std::sync::MutexGuard<'_, Shared> {
+        let mut shared = self.shared.lock().unwrap();
+        shared.open = false;
+        self.reader_shows
+            .store(shared.reader_showed, Ordering::Relaxed);
+        let _ = self.window.run_function_wait(|mut window_handle| {
+            window_handle.set_overlays_visible(true);
+        });
+        shared
+    }
+
+    pub fn resize(&self, size: UVec2) {
+        {
+            let mut shared = self.shared.lock().unwrap();
+            shared.state.size = size.max(UVec2::ONE);
+            shared.state.scroll_to_selection();
+        }
+        self.refresh();
+    }
+
+    pub fn move_selection(&self, direction: GalleryMove) {
+        self.shared.lock().unwrap().state.move_selection(direction);
+        self.refresh();
+    }
+
+    pub fn scroll(&self, rows: f32) {
+        self.shared.lock().unwrap().state.scroll(rows);
+        self.refresh();
+    }
+
+    /// Select the image clicked at `position`. Returns true if it was
+    /// clicked twice in a row, to open it.
+    pub fn click(&mut self, position: Vec2) -> bool {
+        let Some(index) = self.shared.lock().unwrap().state.index_at(position) else {
+            return false;
+        };
+        self.shared.lock().unwrap().state.selected = index;
+        self.refresh();
+
+        let now = Instant::now();
+        let double = self
+            .last_click
+            .is_some_and(|(at, clicked)| clicked == index && now - at < DOUBLE_CLICK);
+        self.last_click = (!double).then_some((now, index));
+        double
+    }
+
+    /// Thumbnail of image `index` if it is made, requested otherwise.
+    pub fn thumbnail(&self, index: usize) -> Option<RgbaImage> {
+        let mut shared = self.shared.lock().unwrap();
+        match &shared.thumbs[index] {
+            Thumb::Ready(thumb) => return Some(thumb.clone()),
+            Thumb::Missing => shared.thumbs[index] = Thumb::Pending,
+            Thumb::Pending | Thumb::Failed => return None,
+        }
+        drop(shared);
+        self.request(index, false);
+        None
+    }
+
+    /// Request the thumbnails in view, and draw the grid with what is ready.
+    pub fn refresh(&self) {
+        let missing: Vec<usize> = {
+            let mut shared = self.shared.lock().unwrap();
+            let missing = shared
+                .state
+                .visible()
+                .filter(|&index| matches!(shared.thumbs[index], Thumb::Missing))
+                .collect::<Vec<_>>();
+            for &index in &missing {
+                shared.thumbs[index] = Thumb::Pending;
+            }
+            missing
+        };
+        for index in missing {
+            self.request(index, true);
+        }
+        present(&self.window, &self.shared, &self.paths);
+    }
+
+    /// Make the thumbnail of image `index` in the background, for the cells
+    /// in view if `for_cell`.
+    fn request(&self, index: usize, for_cell: bool) {
+        let shared = self.shared.clone();
+        let window = self.window.clone();
+        let paths = self.paths.clone();
+        let cache = self.cache.clone();
+        let cache_side = self.cache_side;
+        let options = self.options.clone();
+        let thumb_options = self.thumb_options.clone();
+        let background = self.background.clone();
+        self.pool.execute(move || {
+            let around = {
+                let mut shared = shared.lock().unwrap();
+                let state = &shared.state;
+                // Scrolled away meanwhile, not worth holding the pool for
+                if for_cell && !(shared.open && state.visible().contains(&index)) {
+                    shared.thumbs[index] = Thumb::Missing;
+                    return;
+                }
+                suggested_items_to_cache(state.selected, state.len, cache_side)
+            };
+            // Unread images around the selection are decoded for the
+            // cache, the file reader waits for them if asked meanwhile
+            let (cached, claimed) = {
+                let mut cache = cache.write().unwrap();
+                match &cache[index] {
+                    FileStatus::Read(pair) | FileStatus::Thumbnail(pair) => {
+                        (pair.image_clone(), false)
+                    }
+                    FileStatus::Unread if around.contains(&index) => {
+                        cache[index] = FileStatus::Reading;
+                        (None, true)
+                    }
+                    _ => (None, false),
+                }
+            };
+            let path = &paths[index];
+            let image = match cached {
+                Some(image) => Some(image),
+                None if claimed => {
+                    let status = FileStatus::from((path.clone(), open_image(path, &options)));
+                    let image = match &status {
+                        FileStatus::Read(pair) => pair.image_clone(),
+                        _ => None,
+                    };
+                    cache.write().unwrap()[index] = status;
+                    image
+                }
+                None => open_image(path, &thumb_options)
+                    .ok()
+                    .map(|(image, _)| image),
+            };
+            let thumb = match image {
+                Some(image) => {
+                    let thumb = generate_thumbnail(&image, THUMB_SIZE).unwrap_or(image);
+                    let size = Vec2::new(thumb.width() as f32, thumb.height() as f32);
+                    let thumb = background.read().unwrap().apply(thumb, size);
+                    Thumb::Ready(thumb.to_rgba8())
+                }
+                None => Thumb::Failed,
+            };
+
+            let visible = {
+                let mut shared = shared.lock().unwrap();
+                shared.thumbs[index] = thumb;
+                shared.open && shared.state.visible().contains(&index)
+            };
+            if visible {
+                present(&window, &shared, &paths);
+            }
+        });
+    }
+}
+
+/// Draw the grid and put it in the window, one canvas pixel per window
+/// pixel.
+fn present(window: &show_image::WindowProxy, shared: &Mutex<Shared>, paths: &[PathBuf]) {
+    let canvas = {
+        let shared = shared.lock().unwrap();
+        if !shared.open {
+            return;
+        }
+        draw(&shared, paths)
+    };
+    let _ = window.set_image("gallery", DynamicImage::ImageRgba8(canvas));
+    let _ = window.run_function_wait(|mut window_handle| {
+        window_handle.set_preserve_aspect_ratio(false);
+        window_handle.set_transform(Affine2::IDENTITY);
+    });
+}
+
+fn draw(shared: &Shared, paths: &[PathBuf]) -> RgbaImage {
+    let state = &shared.state;
+    let mut canvas = RgbaImage::from_pixel(state.size.x, state.size.y, BACKGROUND);
+    let columns = state.columns();
+    let max_chars = (THUMB_SIZE / font::text_width("x", 1)) as usize;
+
+    for index in state.visible() {
+        let column = (index % columns) as i64;
+        let row = (index / columns - state.first_row) as i64;
+        let (x, y) = (column * CELL_WIDTH as i64, row * CELL_HEIGHT as i64);
+
+        if index == state.selected {
+            font::fill_rect(
+                &mut canvas,
+                x + 2,
+                y + 2,
+                CELL_WIDTH - 4,
+                CELL_HEIGHT - 4,
+                SELECTION,
+            );
+            font::fill_rect(
+                &mut canvas,
+                x + 5,
+                y + 5,
+                CELL_WIDTH - 10,
+                CELL_HEIGHT - 10,
+                BACKGROUND,
+            );
+        }
+
+        let (thumb_x, thumb_y) = (x + PADDING as i64, y + PADDING as i64);
+        match &shared.thumbs[index] {
+            Thumb::Ready(thumb) => {
+                // Centered in its square
+                let left = thumb_x + (THUMB_SIZE - thumb.width().min(THUMB_SIZE)) as i64 / 2;
+                let top = thumb_y + (THUMB_SIZE - thumb.height().min(THUMB_SIZE)) as i64 / 2;
+                image::imageops::overlay(&mut canvas, thumb, left, top);
+            }
+            Thumb::Failed => {
+                font::fill_rect(
+                    &mut canvas,
+                    thumb_x,
+                    thumb_y,
+                    THUMB_SIZE,
+                    THUMB_SIZE,
+                    PENDING,
+                );
+                font::draw_text(&mut canvas, thumb_x + 8, thumb_y + 8, "?", 3, FAILED);
+            }
+            Thumb::Missing | Thumb::Pending => {
+                font::fill_rect(
+                    &mut canvas,
+                    thumb_x,
+                    thumb_y,
+                    THUMB_SIZE,
+                    THUMB_SIZE,
+                    PENDING,
+                );
+            }
+        }
+
+        let name = paths[index]
+            .file_name()
+            .unwrap_or(paths[index].as_os_str())
+            .to_string_lossy();
+        let name: String = name.chars().take(max_chars).collect();
+        let label_y = thumb_y + THUMB_SIZE as i64 + 3;
+        font::draw_text(&mut canvas, thumb_x, label_y, &name, 1, TEXT);
+    }
+    canvas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(len: usize) -> GalleryState {
+        // 4 columns, 3 rows
+        GalleryState {
+            len,
+            selected: 0,
+            first_row: 0,
+            scrolled: 0.0,
+            size: UVec2::new(CELL_WIDTH * 4 + 10, CELL_HEIGHT * 3 + 10),
+        }
+    }
+
+    #[test]
+    fn test_selection_scrolls() {
+        let mut state = state(30);
+        assert_eq!(0..16, state.visible());
+
+        state.move_selection(GalleryMove::Down);
+        assert_eq!(4, state.selected);
+        state.move_selection(GalleryMove::PageDown);
+        assert_eq!((16, 2), (state.selected, state.first_row));
+        state.move_selection(GalleryMove::Last);
+        assert_eq!((29, 5), (state.selected, state.first_row));
+        // No cell below on the last row
+        state.move_selection(GalleryMove::Down);
+        assert_eq!(29, state.selected);
+        state.move_selection(GalleryMove::First);
+        assert_eq!((0, 0), (state.selected, state.first_row));
+
+        state.scroll(100.0);
+        assert_eq!(5, state.first_row);
+        state.scroll(-2.0);
+        assert_eq!(3, state.first_row);
+        // Touchpads scroll by fractions of rows
+        for _ in 0..3 {
+            state.scroll(-0.5);
+        }
+        assert_eq!(2, state.first_row);
+        state.scroll(0.25);
+        assert_eq!(2, state.first_row);
+        state.scroll(1.25);
+        assert_eq!(3, state.first_row);
+    }
+
+    #[test]
+    fn test_index_at() {
+        let mut state = state(30);
+        let cell = Vec2::new(CELL_WIDTH as f32, CELL_HEIGHT as f32);
+        assert_eq!(Some(0), state.index_at(Vec2::splat(1.0)));
+        assert_eq!(Some(5), state.index_at(cell * 1.5));
+        // Past the last column, or the last image
+        assert_eq!(None, state.index_at(Vec2::new(cell.x * 4.5, 1.0)));
+        state.first_row = 5;
+        assert_eq!(None, state.index_at(cell * 2.5));
+    }
+}
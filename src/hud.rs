@@ -0,0 +1,193 @@
+use image::{Rgba, RgbaImage};
+use show_image::glam::Vec2;
+use std::path::Path;
+
+use crate::font;
+use crate::fs_utils::ImageDetails;
+use crate::view::ViewState;
+
+/// Font pixels per window pixel, when the overlay is fine enough.
+const SCALE: u32 = 2;
+const PADDING: u32 = 8;
+/// Distance from the top left corner of the visible part of the image.
+const MARGIN: f32 = 12.0;
+/// Longest side of the overlay. The text is drawn larger when the image is
+/// zoomed in so much that a texel covers several window pixels.
+const MAX_SIDE: f32 = 2048.0;
+
+const BACKGROUND: Rgba<u8> = Rgba([0, 0, 0, 170]);
+const TEXT: Rgba<u8> = Rgba([235, 235, 235, 255]);
+
+/// What there is to tell about the file at `path`, the `index`th of `total`,
+/// for `details` of how it was decoded or None if it couldn't be.
+pub fn describe(
+    path: &Path,
+    index: usize,
+    total: usize,
+    details: Option<&ImageDetails>,
+) -> Vec<String> {
+    let name = path
+        .file_name()
+        .unwrap_or(path.as_os_str())
+        .to_string_lossy();
+    let mut lines = vec![format!("{}  {}/{}", name, index + 1, total)];
+
+    let mut file = Vec::new();
+    if let Some(details) = details {
+        file.push(format!("{}x{}", details.size.0, details.size.1));
+    }
+    if let Ok(metadata) = std::fs::metadata(path) {
+        file.push(human_size(metadata.len()));
+    }
+    if let Some(extension) = path.extension() {
+        file.push(extension.to_string_lossy().to_uppercase());
+    }
+    lines.push(file.join("  "));
+
+    match details {
+        Some(details) => {
+            let mut state = vec![if details.thumbnail {
+                "preview, loading full image".to_string()
+            } else {
+                "full image".to_string()
+            }];
+            if details.subimages > 1 {
+                state.push(format!(
+                    "page {}/{}",
+                    details.subimage + 1,
+                    details.subimages
+                ));
+            }
+            if let Some((w, h)) = details.sensor_size {
+                state.push(format!("sensor {}x{}", w, h));
+            }
+            lines.push(state.join(", "));
+        }
+        None => lines.push("could not be decoded".to_string()),
+    }
+    lines
+}
+
+/// How the image is shown, for humans.
+pub fn describe_view(view: &ViewState) -> String {
+    let mut parts = vec![format!("zoom {:.0}%", view.zoom * 100.0)];
+    if view.rotation() != 0.0 {
+        parts.push(format!("rotated {}°", view.rotation()));
+    }
+    parts.extend(view.flip_description().map(String::from));
+    parts.join(", ")
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// `lines` in a box at the top left of the window, as an overlay for an
+/// image of size `image` shown by `view` in a window of size `window`. It
+/// can only be seen over the image, so it goes in the corner of the part of
+/// the window the image covers.
+pub fn overlay(lines: &[String], view: &ViewState, image: Vec2, window: Vec2) -> RgbaImage {
+    // Larger text when texels are too coarse for it
+    let scale = SCALE * (view.zoom / texels(view, image)).ceil().max(1.0) as u32;
+    let text_width = lines
+        .iter()
+        .map(|line| font::text_width(line, scale))
+        .max()
+        .unwrap_or(0);
+    let padding = PADDING * scale / SCALE;
+    let mut hud = RgbaImage::from_pixel(
+        text_width + 2 * padding,
+        lines.len() as u32 * font::line_height(scale) + 2 * padding,
+        BACKGROUND,
+    );
+    for (n, line) in lines.iter().enumerate() {
+        let y = padding + n as u32 * font::line_height(scale);
+        font::draw_text(&mut hud, padding as i64, y as i64, line, scale, TEXT);
+    }
+
+    let (covered, _) = covered(view, image, window);
+    place(&hud, covered + MARGIN, view, image, window)
+}
+
+/// Texels per image pixel of overlays, about one per window pixel if not
+/// too many.
+fn texels(view: &ViewState, image: Vec2) -> f32 {
+    view.zoom.min(MAX_SIDE / image.max_element())
+}
+
+/// Top left and bottom right corners of the part of the window covered by
+/// an image of size `image` shown by `view`.
+pub fn covered(view: &ViewState, image: Vec2, window: Vec2) -> (Vec2, Vec2) {
+    let transform = view.transform(image, window);
+    let corners = [Vec2::ZERO, Vec2::X, Vec2::Y, Vec2::ONE]
+        .map(|corner| transform.transform_point2(corner) * window);
+    let (min, max) = corners.iter().fold(
+        (Vec2::splat(f32::INFINITY), Vec2::splat(f32::NEG_INFINITY)),
+        |(min, max), corner| (min.min(*corner), max.max(*corner)),
+    );
+    (min.max(Vec2::ZERO), max.min(window))
+}
+
+/// An overlay showing `picture` upright with its top left corner at
+/// `origin` on screen.
+///
+/// Overlays are stretched and turned along with the image, so the picture
+/// is drawn where it lands in an overlay covering an image of size `image`,
+/// shown by `view` in a window of size `window`.
+pub fn place(
+    picture: &RgbaImage,
+    origin: Vec2,
+    view: &ViewState,
+    image: Vec2,
+    window: Vec2,
+) -> RgbaImage {
+    let size = (image * texels(view, image)).ceil().max(Vec2::ONE);
+    let mut overlay = RgbaImage::new(size.x as u32, size.y as u32);
+    let picture_size = Vec2::new(picture.width() as f32, picture.height() as f32);
+    let transform = view.transform(image, window);
+
+    // Texels under the picture, found from its corners
+    let to_overlay = |screen: Vec2| transform.inverse().transform_point2(screen / window) * size;
+    let (mut min, mut max) = (Vec2::splat(f32::INFINITY), Vec2::splat(f32::NEG_INFINITY));
+    for corner in [Vec2::ZERO, Vec2::X, Vec2::Y, Vec2::ONE] {
+        let texel = to_overlay(origin + corner * picture_size);
+        min = min.min(texel);
+        max = max.max(texel);
+    }
+    let min = min.floor().max(Vec2::ZERO);
+    let max = max.ceil().min(size);
+
+    for y in min.y as u32..max.y as u32 {
+        for x in min.x as u32..max.x as u32 {
+            let texel = (Vec2::new(x as f32, y as f32) + 0.5) / size;
+            let on_picture = transform.transform_point2(texel) * window - origin;
+            if on_picture.cmpge(Vec2::ZERO).all() && on_picture.cmplt(picture_size).all() {
+                let pixel = *picture.get_pixel(on_picture.x as u32, on_picture.y as u32);
+                overlay.put_pixel(x, y, pixel);
+            }
+        }
+    }
+    overlay
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_human_size() {
+        assert_eq!("512 B", human_size(512));
+        assert_eq!("1.5 KB", human_size(1536));
+        assert_eq!("2.0 MB", human_size(2 * 1024 * 1024));
+    }
+}
@@ -0,0 +1,80 @@
+use image::{DynamicImage, GenericImageView};
+
+/// Coordinates and value of the pixel at (x, y): as 8-bit RGBA and hex, then
+/// as stored when that says more (gray levels, 16-bit or float channels).
+pub fn describe_pixel(image: &DynamicImage, x: u32, y: u32) -> Option<String> {
+    if !image.in_bounds(x, y) {
+        return None;
+    }
+    let [r, g, b, a] = image.get_pixel(x, y).0;
+    let mut text = format!(
+        "{}, {}  rgba({}, {}, {}, {})  #{:02X}{:02X}{:02X}{:02X}",
+        x, y, r, g, b, a, r, g, b, a
+    );
+
+    let list = |values: &[String]| values.join(", ");
+    let stored = match image {
+        DynamicImage::ImageLuma8(image) => Some(format!("luma({})", image.get_pixel(x, y).0[0])),
+        DynamicImage::ImageLumaA8(image) => {
+            let [l, a] = image.get_pixel(x, y).0;
+            Some(format!("luma({}, {})", l, a))
+        }
+        DynamicImage::ImageLuma16(image) => Some(format!("luma16({})", image.get_pixel(x, y).0[0])),
+        DynamicImage::ImageLumaA16(image) => {
+            let [l, a] = image.get_pixel(x, y).0;
+            Some(format!("luma16({}, {})", l, a))
+        }
+        DynamicImage::ImageRgb16(image) => Some(format!(
+            "rgb16({})",
+            list(&image.get_pixel(x, y).0.map(|c| c.to_string()))
+        )),
+        DynamicImage::ImageRgba16(image) => Some(format!(
+            "rgba16({})",
+            list(&image.get_pixel(x, y).0.map(|c| c.to_string()))
+        )),
+        DynamicImage::ImageRgb32F(image) => Some(format!(
+            "rgb32f({})",
+            list(&image.get_pixel(x, y).0.map(|c| format!("{:.4}", c)))
+        )),
+        DynamicImage::ImageRgba32F(image) => Some(format!(
+            "rgba32f({})",
+            list(&image.get_pixel(x, y).0.map(|c| format!("{:.4}", c)))
+        )),
+        _ => None,
+    };
+    if let Some(stored) = stored {
+        text.push_str("  ");
+        text.push_str(&stored);
+    }
+    Some(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Rgba};
+
+    #[test]
+    fn test_describe_pixel() {
+        let image =
+            DynamicImage::ImageRgba8(ImageBuffer::from_pixel(2, 2, Rgba([255, 128, 0, 255])));
+        assert_eq!(
+            Some("1, 0  rgba(255, 128, 0, 255)  #FF8000FF".to_string()),
+            describe_pixel(&image, 1, 0)
+        );
+        assert_eq!(None, describe_pixel(&image, 2, 0));
+
+        let image = DynamicImage::ImageRgba16(ImageBuffer::from_pixel(
+            1,
+            1,
+            Rgba([65535, 32896, 0, 65535]),
+        ));
+        assert_eq!(
+            Some(
+                "0, 0  rgba(255, 128, 0, 255)  #FF8000FF  rgba16(65535, 32896, 0, 65535)"
+                    .to_string()
+            ),
+            describe_pixel(&image, 0, 0)
+        );
+    }
+}
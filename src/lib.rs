@@ -1,2 +1,20 @@
+pub mod background;
+pub mod compare;
+pub mod diff;
+pub mod errors;
+pub mod filmstrip;
+pub mod font;
+pub mod formats;
 pub mod fs_utils;
+pub mod gallery;
+pub mod hud;
+pub mod inspect;
+pub mod pairs;
+pub mod placeholder;
+pub mod raw;
+pub mod sandbox;
+pub mod straighten;
+pub mod subimages;
+pub mod svg;
+pub mod view;
 pub mod window;
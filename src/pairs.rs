@@ -0,0 +1,80 @@
+use anyhow::{anyhow, Result};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use crate::fs_utils::is_supported_image;
+
+/// Images of two directories matched by their path relative to them, e.g.
+/// renders before and after a change.
+#[derive(Debug, Default, PartialEq)]
+pub struct Pairing {
+    /// Relative paths of the images in both directories, sorted.
+    pub both: Vec<PathBuf>,
+    /// Relative paths of the images only in the first directory.
+    pub only_first: Vec<PathBuf>,
+    /// Relative paths of the images only in the second directory.
+    pub only_second: Vec<PathBuf>,
+}
+
+impl Pairing {
+    /// Match the images under `first` and `second`, subdirectories included.
+    pub fn new(first: &Path, second: &Path) -> Result<Pairing> {
+        Ok(Pairing::of(images_under(first)?, images_under(second)?))
+    }
+
+    fn of(first: BTreeSet<PathBuf>, second: BTreeSet<PathBuf>) -> Pairing {
+        Pairing {
+            both: first.intersection(&second).cloned().collect(),
+            only_first: first.difference(&second).cloned().collect(),
+            only_second: second.difference(&first).cloned().collect(),
+        }
+    }
+}
+
+/// Paths relative to `dir` of the supported images under it.
+fn images_under(dir: &Path) -> Result<BTreeSet<PathBuf>> {
+    let mut images = BTreeSet::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(current) = dirs.pop() {
+        let entries = current
+            .read_dir()
+            .map_err(|e| anyhow!("Cannot read {}: {}", current.display(), e))?;
+        for entry in entries.filter_map(|e| e.ok()) {
+            // Not following links to directories, which could lead back up
+            // the tree, only to files
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            let path = entry.path();
+            let is_file = file_type.is_file() || (file_type.is_symlink() && path.is_file());
+            if file_type.is_dir() {
+                dirs.push(path);
+            } else if is_file && is_supported_image(&path) {
+                images.insert(path.strip_prefix(dir)?.to_path_buf());
+            }
+        }
+    }
+    Ok(images)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pairing() {
+        let set = |paths: &[&str]| paths.iter().map(PathBuf::from).collect();
+        let pairing = Pairing::of(
+            set(&["b.png", "a.png", "sub/c.png", "old.png"]),
+            set(&["a.png", "sub/c.png", "b.png", "new.png", "c.png"]),
+        );
+        assert_eq!(
+            Pairing {
+                both: vec!["a.png".into(), "b.png".into(), "sub/c.png".into()],
+                only_first: vec!["old.png".into()],
+                only_second: vec!["c.png".into(), "new.png".into()],
+            },
+            pairing
+        );
+    }
+}
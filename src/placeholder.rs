@@ -0,0 +1,43 @@
+use image::{DynamicImage, Rgba, RgbaImage};
+use std::path::Path;
+
+use crate::font;
+
+const WIDTH: u32 = 800;
+const HEIGHT: u32 = 450;
+const MARGIN: u32 = 32;
+
+const BACKGROUND: Rgba<u8> = Rgba([48, 48, 48, 255]);
+const ACCENT: Rgba<u8> = Rgba([230, 90, 80, 255]);
+const TEXT: Rgba<u8> = Rgba([235, 235, 235, 255]);
+const DIM_TEXT: Rgba<u8> = Rgba([170, 170, 170, 255]);
+
+/// Frame shown in place of an image that could not be decoded, telling
+/// which file it is and what went wrong.
+pub fn error_image(path: &Path, error: &str) -> DynamicImage {
+    let mut image = RgbaImage::from_pixel(WIDTH, HEIGHT, BACKGROUND);
+    let name = path
+        .file_name()
+        .unwrap_or(path.as_os_str())
+        .to_string_lossy();
+
+    font::fill_rect(&mut image, 0, 0, WIDTH, 6, ACCENT);
+    let mut y = MARGIN as i64;
+    for (text, scale, color) in [
+        ("Cannot display this file", 3, ACCENT),
+        (name.as_ref(), 2, TEXT),
+        (error, 2, DIM_TEXT),
+    ] {
+        let max_chars = (WIDTH - 2 * MARGIN) / font::text_width("x", scale);
+        for line in font::wrap(text, max_chars as usize) {
+            if y + font::line_height(scale) as i64 > (HEIGHT - MARGIN) as i64 {
+                break;
+            }
+            font::draw_text(&mut image, MARGIN as i64, y, &line, scale, color);
+            y += font::line_height(scale) as i64;
+        }
+        y += font::line_height(2) as i64;
+    }
+
+    DynamicImage::ImageRgba8(image)
+}
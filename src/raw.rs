@@ -0,0 +1,390 @@
+use image::codecs::jpeg::JpegDecoder;
+use image::error::{DecodingError, ImageFormatHint};
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageError};
+use std::collections::HashSet;
+use std::io::Cursor;
+use std::ops::Range;
+use std::path::Path;
+
+use crate::errors::DecodeError;
+use crate::fs_utils::{decode_with_limits, DecodeLimits};
+
+const RAW_EXTENSIONS: &[&str] = &[
+    "cr2", "cr3", "crw", "nef", "nrw", "arw", "srf", "sr2", "dng", "orf", "rw2", "raf", "pef",
+    "srw", "3fr", "iiq",
+];
+
+/// Segments a JPEG stream may have before we give up on it, so that bogus
+/// streams can't make the search quadratic.
+const MAX_SEGMENTS: usize = 1024;
+
+pub fn is_raw(path: impl AsRef<Path>) -> bool {
+    path.as_ref()
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| RAW_EXTENSIONS.iter().any(|r| ext.eq_ignore_ascii_case(r)))
+        .unwrap_or(false)
+}
+
+fn raw_error(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> ImageError {
+    ImageError::Decoding(DecodingError::new(
+        ImageFormatHint::Name("RAW".to_string()),
+        e,
+    ))
+}
+
+/// A camera RAW file, decoded.
+pub struct RawImage {
+    pub image: DynamicImage,
+    /// Size of the sensor data, when we could find it.
+    pub sensor_size: Option<(u32, u32)>,
+}
+
+/// Decode `data`, the camera RAW file at `path`. Unless the `raw` feature is
+/// enabled and the file can be opened again (not in the sandbox), this shows
+/// the largest JPEG preview embedded by the camera.
+#[cfg_attr(not(feature = "raw"), allow(unused_variables))]
+pub fn decode(path: &Path, data: &[u8], limits: &DecodeLimits) -> Result<RawImage, DecodeError> {
+    let tiff = TiffInfo::parse(data);
+
+    #[cfg(feature = "raw")]
+    match demosaic(path, tiff.as_ref().and_then(|t| t.sensor_size), limits) {
+        Ok(image) => {
+            return Ok(RawImage {
+                sensor_size: tiff
+                    .as_ref()
+                    .and_then(|t| t.sensor_size)
+                    .or(Some((image.width(), image.height()))),
+                image,
+            })
+        }
+        Err(e) => log::warn!("Cannot demosaic {:?}, using preview: {}", path, e),
+    }
+
+    let mut previews = embedded_jpegs(data);
+    // Biggest first, keep going if one of them is broken
+    previews.sort_by_key(|p| std::cmp::Reverse(p.width as u64 * p.height as u64));
+
+    let mut last_error = raw_error("no embedded preview found").into();
+    for preview in previews {
+        let jpeg = &data[preview.offset..preview.offset + preview.len];
+        match decode_preview(jpeg, tiff.as_ref().and_then(|t| t.orientation), limits) {
+            Ok(image) => {
+                return Ok(RawImage {
+                    image,
+                    sensor_size: tiff.as_ref().and_then(|t| t.sensor_size),
+                })
+            }
+            Err(e) => last_error = e,
+        }
+    }
+
+    Err(last_error)
+}
+
+#[cfg(feature = "raw")]
+fn demosaic(
+    path: &Path,
+    sensor_size: Option<(u32, u32)>,
+    limits: &DecodeLimits,
+) -> Result<DynamicImage, DecodeError> {
+    let (w, h) = sensor_size.ok_or_else(|| raw_error("unknown sensor size"))?;
+    limits.check(w, h)?;
+    // Working buffers of 16 bits per channel, before the 8 bit result
+    limits.check_alloc(u64::from(w) * u64::from(h) * 3 * 2)?;
+    let decoded = imagepipe::simple_decode_8bit(path, 0, 0).map_err(raw_error)?;
+    image::RgbImage::from_raw(decoded.width as u32, decoded.height as u32, decoded.data)
+        .map(DynamicImage::ImageRgb8)
+        .ok_or_else(|| raw_error("unexpected buffer size").into())
+}
+
+fn decode_preview(
+    jpeg: &[u8],
+    orientation: Option<u16>,
+    limits: &DecodeLimits,
+) -> Result<DynamicImage, DecodeError> {
+    let mut decoder = JpegDecoder::new(Cursor::new(jpeg))?;
+    // The orientation of the RAW container wins over the one of the preview
+    let orientation = match orientation.and_then(|o| Orientation::from_exif(o as u8)) {
+        Some(o) => o,
+        None => decoder.orientation()?,
+    };
+    let mut image = decode_with_limits(decoder, limits)?;
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct EmbeddedJpeg {
+    offset: usize,
+    len: usize,
+    width: u16,
+    height: u16,
+}
+
+fn be16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*data.get(at)?, *data.get(at + 1)?]))
+}
+
+/// Find the (baseline or progressive) JPEG streams stored in `data`.
+fn embedded_jpegs(data: &[u8]) -> Vec<EmbeddedJpeg> {
+    let mut found = Vec::new();
+    let mut scanned = 0..0;
+    let mut i = 0;
+    while i + 3 < data.len() {
+        if data[i] == 0xFF && data[i + 1] == 0xD8 && data[i + 2] == 0xFF {
+            if let Some(jpeg) = parse_jpeg(data, i, &mut scanned) {
+                i = jpeg.offset + jpeg.len;
+                found.push(jpeg);
+                continue;
+            }
+        }
+        i += 1;
+    }
+    found
+}
+
+/// The JPEG stream at `offset` in `data`, if there is one. `scanned` is the
+/// entropy coded data last gone through, kept across calls.
+fn parse_jpeg(data: &[u8], offset: usize, scanned: &mut Range<usize>) -> Option<EmbeddedJpeg> {
+    let mut size = None;
+    let mut p = offset + 2;
+
+    for _ in 0..MAX_SEGMENTS {
+        if *data.get(p)? != 0xFF {
+            return None;
+        }
+        // Skip fill bytes
+        while *data.get(p + 1)? == 0xFF {
+            p += 1;
+        }
+        let marker = data[p + 1];
+        match marker {
+            0xD9 => {
+                let (width, height) = size?;
+                return Some(EmbeddedJpeg {
+                    offset,
+                    len: p + 2 - offset,
+                    width,
+                    height,
+                });
+            }
+            0x01 | 0xD0..=0xD7 => {
+                p += 2;
+                continue;
+            }
+            _ => {}
+        }
+
+        let segment_len = be16(data, p + 2)? as usize;
+        match marker {
+            // Baseline, extended and progressive: the only ones we can decode
+            0xC0..=0xC2 => size = Some((be16(data, p + 7)?, be16(data, p + 5)?)),
+            // Lossless (raw sensor data in CR2/DNG), arithmetic, ...
+            0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF => return None,
+            _ => {}
+        }
+        p += 2 + segment_len;
+
+        if marker == 0xDA {
+            // Bogus streams may start anywhere in the same data, which ends
+            // in the same place for all of them
+            if !scanned.contains(&p) {
+                *scanned = p..entropy_coded_end(data, p);
+            }
+            p = scanned.end;
+        }
+    }
+    None
+}
+
+/// Where the entropy coded data at `p` ends: at the next marker that is not
+/// a stuffed byte or a restart marker, or else at the end of `data`.
+fn entropy_coded_end(data: &[u8], mut p: usize) -> usize {
+    while p + 1 < data.len() {
+        let next = data[p + 1];
+        if data[p] == 0xFF && next != 0x00 && !(0xD0..=0xD7).contains(&next) {
+            return p;
+        }
+        p += 1;
+    }
+    data.len()
+}
+
+/// What we can learn from a TIFF based RAW (CR2, NEF, ARW, DNG, ...).
+#[derive(Debug, Default, PartialEq, Eq)]
+struct TiffInfo {
+    orientation: Option<u16>,
+    sensor_size: Option<(u32, u32)>,
+}
+
+impl TiffInfo {
+    fn parse(data: &[u8]) -> Option<TiffInfo> {
+        let little = match data.get(0..2)? {
+            b"II" => true,
+            b"MM" => false,
+            _ => return None,
+        };
+        let reader = TiffReader { data, little };
+        // 42 for TIFF, the others for ORF and RW2
+        if ![42, 0x4F52, 0x5352, 0x55].contains(&reader.u16(2)?) {
+            return None;
+        }
+
+        let mut info = TiffInfo::default();
+        let mut best_area = 0u64;
+        let mut visited = HashSet::new();
+        let mut queue = vec![reader.u32(4)? as usize];
+
+        while let Some(ifd) = queue.pop() {
+            if ifd == 0 || !visited.insert(ifd) || visited.len() > 64 {
+                continue;
+            }
+            let Some(count) = reader.u16(ifd) else {
+                continue;
+            };
+
+            let mut width = None;
+            let mut height = None;
+            let mut full_resolution = true;
+            for n in 0..count as usize {
+                let entry = ifd + 2 + n * 12;
+                let (Some(tag), Some(value)) = (reader.u16(entry), reader.value(entry)) else {
+                    break;
+                };
+                match tag {
+                    0x00FE => full_resolution = value == 0,
+                    0x0100 => width = Some(value),
+                    0x0101 => height = Some(value),
+                    0x0112 if visited.len() == 1 => info.orientation = Some(value as u16),
+                    // SubIFDs
+                    0x014A => queue.extend(reader.offsets(entry)),
+                    _ => {}
+                }
+            }
+
+            if let (Some(w), Some(h), true) = (width, height, full_resolution) {
+                if w as u64 * h as u64 > best_area {
+                    best_area = w as u64 * h as u64;
+                    info.sensor_size = Some((w, h));
+                }
+            }
+
+            if let Some(next) = reader.u32(ifd + 2 + count as usize * 12) {
+                queue.push(next as usize);
+            }
+        }
+
+        Some(info)
+    }
+}
+
+struct TiffReader<'a> {
+    data: &'a [u8],
+    little: bool,
+}
+
+impl TiffReader<'_> {
+    fn u16(&self, at: usize) -> Option<u16> {
+        let b = [*self.data.get(at)?, *self.data.get(at + 1)?];
+        Some(if self.little {
+            u16::from_le_bytes(b)
+        } else {
+            u16::from_be_bytes(b)
+        })
+    }
+
+    fn u32(&self, at: usize) -> Option<u32> {
+        let b: [u8; 4] = self.data.get(at..at + 4)?.try_into().ok()?;
+        Some(if self.little {
+            u32::from_le_bytes(b)
+        } else {
+            u32::from_be_bytes(b)
+        })
+    }
+
+    /// First value of a SHORT or LONG entry.
+    fn value(&self, entry: usize) -> Option<u32> {
+        match self.u16(entry + 2)? {
+            3 => self.u16(entry + 8).map(u32::from),
+            4 | 13 => self.u32(entry + 8),
+            _ => None,
+        }
+    }
+
+    /// All the values of a LONG (or IFD) entry holding offsets.
+    fn offsets(&self, entry: usize) -> Vec<usize> {
+        let count = self.u32(entry + 4).unwrap_or(0) as usize;
+        let start = if count <= 1 {
+            entry + 8
+        } else {
+            self.u32(entry + 8).unwrap_or(0) as usize
+        };
+        (0..count.min(16))
+            .filter_map(|n| self.u32(start + n * 4))
+            .map(|x| x as usize)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jpeg(width: u32, height: u32) -> Vec<u8> {
+        let mut out = Vec::new();
+        DynamicImage::new_rgb8(width, height)
+            .write_to(&mut Cursor::new(&mut out), image::ImageFormat::Jpeg)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn test_embedded_jpegs() {
+        let small = jpeg(16, 8);
+        let big = jpeg(64, 48);
+        let mut data = b"II*\0garbage".to_vec();
+        data.extend_from_slice(&small);
+        data.extend_from_slice(&[0xFF, 0xD8, 0x00, 0x12]);
+        let big_offset = data.len();
+        data.extend_from_slice(&big);
+        data.extend_from_slice(b"trailer");
+
+        let found = embedded_jpegs(&data);
+        assert_eq!(2, found.len());
+        assert_eq!((16, 8), (found[0].width, found[0].height));
+        assert_eq!(
+            EmbeddedJpeg {
+                offset: big_offset,
+                len: big.len(),
+                width: 64,
+                height: 48
+            },
+            found[1]
+        );
+    }
+
+    #[test]
+    fn test_bogus_jpegs() {
+        // Streams all leading to the same never-ending data
+        let sos = 60_000;
+        let mut data = vec![0x55; sos];
+        for at in (4..sos - 6).step_by(6) {
+            let skip = (sos - at - 4) as u16;
+            data[at..at + 6].copy_from_slice(&[
+                0xFF,
+                0xD8,
+                0xFF,
+                0xFE,
+                (skip >> 8) as u8,
+                skip as u8,
+            ]);
+        }
+        data.extend_from_slice(&[0xFF, 0xDA, 0x00, 0x02]);
+        data.extend(std::iter::repeat_n(0x55, 1 << 20));
+        let start = std::time::Instant::now();
+        assert!(embedded_jpegs(&data).is_empty());
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,453 @@
+use image::{ColorType, DynamicImage, ImageBuffer};
+use log::debug;
+use std::ffi::{OsStr, OsString};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::errors::DecodeError;
+use crate::fs_utils::{
+    decode_data, read_image, DecodeLimits, DecodeResult, ImageDetails, LoadOptions,
+};
+use crate::svg;
+
+/// First argument telling the executable to act as a decoding worker.
+pub const WORKER_ARG: &str = "--decode-worker";
+
+/// CPU time a worker may use when decodes have no timeout, so that one stuck
+/// in a loop still gets killed eventually.
+const DEFAULT_CPU_LIMIT: Duration = Duration::from_secs(600);
+
+// Leading byte of the worker answer
+const OK: u8 = 0;
+const ERR_MESSAGE: u8 = 1;
+const ERR_TOO_LARGE: u8 = 2;
+const ERR_TOO_MUCH_MEMORY: u8 = 3;
+
+/// Decode a file in a short-lived child process (this same executable, run
+/// with `WORKER_ARG`), so that a crashing or compromised decoder can't take
+/// the viewer down with it.
+pub fn decode_in_child(path: &Path, index: usize, options: &LoadOptions) -> DecodeResult {
+    let exe = std::env::current_exe()?;
+    debug!("Decode {:?} in a child process", path);
+
+    let mut child = Command::new(exe)
+        .args(worker_args(path, index, options))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    // Read on the side, so that a big answer can't fill the pipe and stall
+    // the child while we wait for it
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let reader = std::thread::spawn(move || {
+        let mut answer = Vec::new();
+        stdout.read_to_end(&mut answer).map(|_| answer)
+    });
+
+    let status = match options.timeout {
+        Some(timeout) => wait_timeout(&mut child, timeout)?,
+        None => Some(child.wait()?),
+    };
+    let Some(status) = status else {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(DecodeError::TimedOut {
+            after: options.timeout.unwrap_or_default(),
+        });
+    };
+    let answer = reader
+        .join()
+        .map_err(|_| DecodeError::Worker("cannot read decoder answer".to_string()))??;
+
+    if !status.success() && answer.first() != Some(&ERR_MESSAGE) {
+        return Err(DecodeError::Worker(format!("decoder {}", status)));
+    }
+
+    parse_answer(&answer)
+}
+
+/// Wait for `child` to exit, for at most `timeout`.
+fn wait_timeout(child: &mut Child, timeout: Duration) -> std::io::Result<Option<ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn worker_args(path: &Path, index: usize, options: &LoadOptions) -> Vec<OsString> {
+    let limits = &options.limits;
+    [
+        WORKER_ARG.to_string(),
+        index.to_string(),
+        limits.max_width.to_string(),
+        limits.max_height.to_string(),
+        limits.max_alloc.to_string(),
+        options.svg_size.0.to_string(),
+        options.svg_size.1.to_string(),
+        options
+            .timeout
+            .map_or(0, |t| t.as_millis() as u64)
+            .to_string(),
+    ]
+    .into_iter()
+    .map(OsString::from)
+    .chain(std::iter::once(path.as_os_str().to_owned()))
+    .collect()
+}
+
+/// Entry point of the worker process: decode the file named in `args` (as
+/// built by `worker_args`, after `WORKER_ARG`) and write the outcome to stdout.
+pub fn run_worker(args: &[OsString]) -> ! {
+    let parse = |n: usize| -> Option<u64> { args.get(n)?.to_str()?.parse().ok() };
+    let parsed = (|| {
+        let index = parse(0)? as usize;
+        let options = LoadOptions {
+            limits: DecodeLimits {
+                max_width: parse(1)? as u32,
+                max_height: parse(2)? as u32,
+                max_alloc: parse(3)?,
+            },
+            svg_size: (parse(4)? as u32, parse(5)? as u32),
+            timeout: match parse(6)? {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+            ..Default::default()
+        };
+        Some((PathBuf::from(args.get(7)?), index, options))
+    })();
+
+    let Some((path, index, options)) = parsed else {
+        eprintln!("Invalid arguments for {}", WORKER_ARG);
+        std::process::exit(2);
+    };
+
+    // Everything the decoder needs from the file system, as it won't be
+    // allowed to open files once restricted
+    let data = read_image(&path);
+    if svg::is_svg(&path) {
+        svg::preload_fonts();
+    }
+
+    let (answer, code) = match restrict(&options) {
+        Ok(()) => {
+            let decoded = data
+                .map_err(DecodeError::from)
+                .and_then(|data| decode_data(&path, &data, index, &options));
+            (encode_answer(decoded), 0)
+        }
+        // Better no image than one decoded unprotected
+        Err(e) => {
+            let msg = format!("sandbox unavailable: {}", e);
+            (encode_answer(Err(DecodeError::Worker(msg))), 1)
+        }
+    };
+    let mut stdout = std::io::stdout().lock();
+    let res = stdout.write_all(&answer).and_then(|_| stdout.flush());
+    std::process::exit(if res.is_ok() { code } else { 1 });
+}
+
+/// Drop what a decoder has no business doing: set rlimits, and only allow
+/// the syscalls needed to compute and write an answer from memory.
+#[cfg(target_os = "linux")]
+fn restrict(options: &LoadOptions) -> Result<(), Box<dyn std::error::Error>> {
+    use seccompiler::{
+        BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition, SeccompFilter,
+        SeccompRule, TargetArch,
+    };
+    use std::collections::BTreeMap;
+
+    let set_limit = |resource, value: u64| {
+        let limit = libc::rlimit {
+            rlim_cur: value as libc::rlim_t,
+            rlim_max: value as libc::rlim_t,
+        };
+        // SAFETY: plain syscall on a valid struct
+        match unsafe { libc::setrlimit(resource, &limit) } {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    };
+    // Room for the decoder allocations on top of what is mapped already (the
+    // executable, preloaded fonts), with some slack for the allocator
+    let mapped = mapped_bytes()?;
+    set_limit(
+        libc::RLIMIT_AS,
+        mapped + options.limits.max_alloc + 512 * 1024 * 1024,
+    )?;
+    // A decoder stuck in a loop gets killed instead of lingering, about when
+    // the parent stops waiting for it (CPU time adds up over the threads)
+    let cpu_limit = options.timeout.unwrap_or(DEFAULT_CPU_LIMIT);
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get() as u64);
+    set_limit(libc::RLIMIT_CPU, cpu_limit.as_secs().max(1) * cores)?;
+    set_limit(libc::RLIMIT_FSIZE, 0)?;
+    set_limit(libc::RLIMIT_NOFILE, 64)?;
+    set_limit(libc::RLIMIT_CORE, 0)?;
+
+    let allowed = [
+        // Memory
+        libc::SYS_brk,
+        libc::SYS_mmap,
+        libc::SYS_munmap,
+        libc::SYS_mremap,
+        libc::SYS_mprotect,
+        libc::SYS_madvise,
+        // The answer and diagnostics, on the descriptors we already have
+        libc::SYS_read,
+        libc::SYS_write,
+        libc::SYS_writev,
+        libc::SYS_lseek,
+        libc::SYS_fstat,
+        libc::SYS_close,
+        // Threads of the decoders
+        libc::SYS_futex,
+        libc::SYS_set_robust_list,
+        libc::SYS_rseq,
+        libc::SYS_sched_yield,
+        libc::SYS_sched_getaffinity,
+        libc::SYS_getpid,
+        libc::SYS_gettid,
+        libc::SYS_clock_gettime,
+        libc::SYS_clock_nanosleep,
+        libc::SYS_getrandom,
+        // Signals, for panics and aborts
+        libc::SYS_rt_sigaction,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_sigaltstack,
+        libc::SYS_tgkill,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+    ];
+    let mut rules: BTreeMap<_, _> = allowed.into_iter().map(|n| (n, vec![])).collect();
+    // Threads may be started, not processes
+    let thread = SeccompCondition::new(
+        0,
+        SeccompCmpArgLen::Qword,
+        SeccompCmpOp::MaskedEq(libc::CLONE_THREAD as u64),
+        libc::CLONE_THREAD as u64,
+    )?;
+    rules.insert(libc::SYS_clone, vec![SeccompRule::new(vec![thread])?]);
+    // The flags of clone3 can't be checked: the first filter makes it look
+    // unsupported, so that the C library falls back to clone
+    rules.insert(libc::SYS_clone3, vec![]);
+
+    let arch = TargetArch::try_from(std::env::consts::ARCH)?;
+    let no_clone3 = SeccompFilter::new(
+        BTreeMap::from([(libc::SYS_clone3, vec![])]),
+        SeccompAction::Allow,
+        SeccompAction::Errno(libc::ENOSYS as u32),
+        arch,
+    )?;
+    // Whatever isn't allowed fails with EPERM. Applied last, as it doesn't
+    // allow applying filters.
+    let allow_list = SeccompFilter::new(
+        rules,
+        SeccompAction::Errno(libc::EPERM as u32),
+        SeccompAction::Allow,
+        arch,
+    )?;
+    for filter in [no_clone3, allow_list] {
+        seccompiler::apply_filter(&BpfProgram::try_from(filter)?)?;
+    }
+    Ok(())
+}
+
+/// Address space used by this process so far.
+#[cfg(target_os = "linux")]
+fn mapped_bytes() -> std::io::Result<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm")?;
+    let pages: u64 = statm
+        .split_whitespace()
+        .next()
+        .and_then(|pages| pages.parse().ok())
+        .ok_or_else(|| std::io::Error::other("malformed /proc/self/statm"))?;
+    // SAFETY: no preconditions
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Ok(pages * page_size as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn restrict(_options: &LoadOptions) -> Result<(), Box<dyn std::error::Error>> {
+    Ok(())
+}
+
+fn color_code(color: ColorType) -> Option<u8> {
+    Some(match color {
+        ColorType::L8 => 0,
+        ColorType::La8 => 1,
+        ColorType::Rgb8 => 2,
+        ColorType::Rgba8 => 3,
+        ColorType::L16 => 4,
+        ColorType::La16 => 5,
+        ColorType::Rgb16 => 6,
+        ColorType::Rgba16 => 7,
+        ColorType::Rgb32F => 8,
+        ColorType::Rgba32F => 9,
+        _ => return None,
+    })
+}
+
+fn push_u32(out: &mut Vec<u8>, n: u32) {
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+fn encode_answer(res: DecodeResult) -> Vec<u8> {
+    let mut out = Vec::new();
+    match res {
+        Ok((image, details)) => {
+            // Rare layouts travel as the closest common one
+            let image = match color_code(image.color()) {
+                Some(_) => image,
+                None => DynamicImage::ImageRgba32F(image.to_rgba32f()),
+            };
+            out.push(OK);
+            push_u32(&mut out, image.width());
+            push_u32(&mut out, image.height());
+            out.push(color_code(image.color()).unwrap());
+            push_u32(&mut out, details.subimage as u32);
+            push_u32(&mut out, details.subimages as u32);
+            let (sensor_w, sensor_h) = details.sensor_size.unwrap_or((0, 0));
+            push_u32(&mut out, sensor_w);
+            push_u32(&mut out, sensor_h);
+            out.extend_from_slice(image.as_bytes());
+        }
+        Err(DecodeError::TooLarge {
+            width,
+            height,
+            max_width,
+            max_height,
+        }) => {
+            out.push(ERR_TOO_LARGE);
+            for n in [width, height, max_width, max_height] {
+                push_u32(&mut out, n);
+            }
+        }
+        Err(DecodeError::TooMuchMemory { max_alloc }) => {
+            out.push(ERR_TOO_MUCH_MEMORY);
+            out.extend_from_slice(&max_alloc.to_le_bytes());
+        }
+        Err(e) => {
+            out.push(ERR_MESSAGE);
+            out.extend_from_slice(e.to_string().as_bytes());
+        }
+    }
+    out
+}
+
+fn parse_answer(answer: &[u8]) -> DecodeResult {
+    let bad = || DecodeError::Worker("malformed answer from decoder".to_string());
+    let u32_at = |at: usize| -> Result<u32, DecodeError> {
+        answer
+            .get(at..at + 4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+            .ok_or_else(bad)
+    };
+
+    match answer.first() {
+        Some(&OK) => {
+            let (width, height) = (u32_at(1)?, u32_at(5)?);
+            let color = *answer.get(9).ok_or_else(bad)?;
+            let details = ImageDetails {
+                subimage: u32_at(10)? as usize,
+                subimages: u32_at(14)? as usize,
+                sensor_size: match (u32_at(18)?, u32_at(22)?) {
+                    (0, 0) => None,
+                    size => Some(size),
+                },
+                ..Default::default()
+            };
+            let image = image_from_bytes(width, height, color, &answer[26..]).ok_or_else(bad)?;
+            Ok((image, details))
+        }
+        Some(&ERR_TOO_LARGE) => Err(DecodeError::TooLarge {
+            width: u32_at(1)?,
+            height: u32_at(5)?,
+            max_width: u32_at(9)?,
+            max_height: u32_at(13)?,
+        }),
+        Some(&ERR_TOO_MUCH_MEMORY) => Err(DecodeError::TooMuchMemory {
+            max_alloc: answer
+                .get(1..9)
+                .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+                .ok_or_else(bad)?,
+        }),
+        Some(&ERR_MESSAGE) => Err(DecodeError::Worker(
+            String::from_utf8_lossy(&answer[1..]).into_owned(),
+        )),
+        _ => Err(bad()),
+    }
+}
+
+fn image_from_bytes(width: u32, height: u32, color: u8, bytes: &[u8]) -> Option<DynamicImage> {
+    let u16s = || -> Vec<u16> {
+        bytes
+            .chunks_exact(2)
+            .map(|b| u16::from_ne_bytes([b[0], b[1]]))
+            .collect()
+    };
+    let f32s = || -> Vec<f32> {
+        bytes
+            .chunks_exact(4)
+            .map(|b| f32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+            .collect()
+    };
+    let bytes = || bytes.to_vec();
+
+    match color {
+        0 => ImageBuffer::from_raw(width, height, bytes()).map(DynamicImage::ImageLuma8),
+        1 => ImageBuffer::from_raw(width, height, bytes()).map(DynamicImage::ImageLumaA8),
+        2 => ImageBuffer::from_raw(width, height, bytes()).map(DynamicImage::ImageRgb8),
+        3 => ImageBuffer::from_raw(width, height, bytes()).map(DynamicImage::ImageRgba8),
+        4 => ImageBuffer::from_raw(width, height, u16s()).map(DynamicImage::ImageLuma16),
+        5 => ImageBuffer::from_raw(width, height, u16s()).map(DynamicImage::ImageLumaA16),
+        6 => ImageBuffer::from_raw(width, height, u16s()).map(DynamicImage::ImageRgb16),
+        7 => ImageBuffer::from_raw(width, height, u16s()).map(DynamicImage::ImageRgba16),
+        8 => ImageBuffer::from_raw(width, height, f32s()).map(DynamicImage::ImageRgb32F),
+        9 => ImageBuffer::from_raw(width, height, f32s()).map(DynamicImage::ImageRgba32F),
+        _ => None,
+    }
+}
+
+/// Whether `arg` asks this executable to be a decoding worker.
+pub fn is_worker_arg(arg: &OsStr) -> bool {
+    arg == WORKER_ARG
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_answer_roundtrip() {
+        let image = DynamicImage::ImageRgb16(ImageBuffer::from_fn(3, 2, |x, y| {
+            image::Rgb([x as u16 * 1000, y as u16, 65535])
+        }));
+        let details = ImageDetails {
+            sensor_size: Some((6000, 4000)),
+            subimage: 1,
+            subimages: 3,
+            ..Default::default()
+        };
+        let (decoded, decoded_details) =
+            parse_answer(&encode_answer(Ok((image.clone(), details.clone())))).unwrap();
+        assert_eq!(image, decoded);
+        assert_eq!(details, decoded_details);
+
+        let err = parse_answer(&encode_answer(Err(DecodeError::TooMuchMemory {
+            max_alloc: 42,
+        })));
+        assert!(matches!(
+            err,
+            Err(DecodeError::TooMuchMemory { max_alloc: 42 })
+        ));
+    }
+}
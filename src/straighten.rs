@@ -0,0 +1,116 @@
+use image::imageops::interpolate_bilinear;
+use image::{DynamicImage, ImageBuffer, Pixel, Rgba, RgbaImage};
+use show_image::glam::{Mat2, Vec2};
+
+use crate::view::ViewState;
+
+/// Grid lines across the longer side of the rotated image.
+const GRID_DIVISIONS: f32 = 12.0;
+const GRID_COLOR: Rgba<u8> = Rgba([255, 255, 255, 150]);
+
+/// Size of the largest upright rectangle fitting in a `size` rectangle
+/// turned by `radians`, centered on it.
+pub fn inscribed_size(size: Vec2, radians: f32) -> Vec2 {
+    let (sin, cos) = radians.sin_cos();
+    let (sin, cos) = (sin.abs(), cos.abs());
+    let (long, short) = (size.max_element(), size.min_element());
+    if short <= 2.0 * sin * cos * long || (sin - cos).abs() < 1e-6 {
+        // Thin enough that the rectangle is held by the two long sides
+        let half = short / 2.0;
+        if size.x >= size.y {
+            Vec2::new(half / sin, half / cos)
+        } else {
+            Vec2::new(half / cos, half / sin)
+        }
+    } else {
+        let cos_2a = cos * cos - sin * sin;
+        Vec2::new(
+            (size.x * cos - size.y * sin) / cos_2a,
+            (size.y * cos - size.x * sin) / cos_2a,
+        )
+    }
+}
+
+/// The image as `view` shows it (flipped, turned and tilted), cropped to the
+/// largest upright rectangle so that the tilt leaves no empty corner.
+pub fn straighten(image: &DynamicImage, view: &ViewState) -> DynamicImage {
+    let mut image = image.clone();
+    if view.flip_horizontal {
+        image = image.fliph();
+    }
+    if view.flip_vertical {
+        image = image.flipv();
+    }
+    image = match view.quarter_turns % 4 {
+        1 => image.rotate90(),
+        2 => image.rotate180(),
+        3 => image.rotate270(),
+        _ => image,
+    };
+    if view.angle == 0.0 {
+        return image;
+    }
+
+    let radians = view.angle.to_radians();
+    // Keep the depth of deep images, 8 bits are enough for the others
+    if image.color().bytes_per_pixel() / image.color().channel_count() > 1 {
+        DynamicImage::ImageRgba16(rotate_and_crop(&image.to_rgba16(), radians))
+    } else {
+        DynamicImage::ImageRgba8(rotate_and_crop(&image.to_rgba8(), radians))
+    }
+}
+
+fn rotate_and_crop<P: Pixel>(
+    image: &ImageBuffer<P, Vec<P::Subpixel>>,
+    radians: f32,
+) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    let size = Vec2::new(image.width() as f32, image.height() as f32);
+    let crop = inscribed_size(size, radians).floor().max(Vec2::ONE);
+    let to_source = Mat2::from_angle(-radians);
+    ImageBuffer::from_fn(crop.x as u32, crop.y as u32, |x, y| {
+        let offset = Vec2::new(x as f32, y as f32) + 0.5 - crop / 2.0;
+        // Pixel centers, kept inside despite rounding
+        let source = (to_source * offset + size / 2.0 - 0.5).clamp(Vec2::ZERO, size - 1.0);
+        interpolate_bilinear(image, source.x, source.y).unwrap()
+    })
+}
+
+/// Lines that stay horizontal and vertical on screen whatever the rotation,
+/// to line the image up with. The overlay is `size` pixels and covers the
+/// image, so it is stretched and turned with it.
+pub fn grid_overlay(view: &ViewState, image: Vec2, size: (u32, u32)) -> RgbaImage {
+    let overlay = Vec2::new(size.0 as f32, size.1 as f32);
+    let scale = overlay / image;
+    let spacing = view.upright_size(image).max_element() / GRID_DIVISIONS;
+    let orientation = view.orientation();
+    RgbaImage::from_fn(size.0, size.1, |x, y| {
+        let point = (Vec2::new(x as f32, y as f32) + 0.5) / overlay;
+        // Screen axes, in image pixels from the center
+        let on_screen = orientation * ((point - 0.5) * image);
+        let distance = |v: f32| (v / spacing - (v / spacing).round()).abs() * spacing;
+        let distance = distance(on_screen.x).min(distance(on_screen.y)) * scale.min_element();
+        let coverage = (1.25 - distance).clamp(0.0, 1.0);
+        let mut color = GRID_COLOR;
+        color[3] = (color[3] as f32 * coverage) as u8;
+        color
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inscribed_size() {
+        let size = Vec2::new(400.0, 200.0);
+        assert_eq!(size, inscribed_size(size, 0.0));
+
+        let square = inscribed_size(Vec2::splat(100.0), 45f32.to_radians());
+        assert!(square.abs_diff_eq(Vec2::splat(100.0 / 2f32.sqrt()), 1e-3));
+
+        // Turned by a small angle, the crop loses a little on both sides
+        let crop = inscribed_size(size, 2f32.to_radians());
+        assert!(crop.x < size.x && crop.y < size.y);
+        assert!(crop.x > 380.0 && crop.y > 180.0);
+    }
+}
@@ -0,0 +1,395 @@
+use image::error::{DecodingError, ImageFormatHint};
+use image::{DynamicImage, ImageBuffer, ImageError, ImageFormat};
+use std::io::Cursor;
+use std::path::Path;
+
+use crate::errors::DecodeError;
+use crate::fs_utils::{decode_with_limits, load_with_limits, DecodeLimits};
+
+fn subimage_error(
+    format: ImageFormat,
+    e: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+) -> ImageError {
+    ImageError::Decoding(DecodingError::new(ImageFormatHint::Exact(format), e))
+}
+
+fn is_multi_image_format(format: ImageFormat) -> bool {
+    matches!(
+        format,
+        ImageFormat::Tiff | ImageFormat::Ico | ImageFormat::Dds
+    )
+}
+
+/// Number of subimages (TIFF pages, icon sizes, DDS mip levels and layers)
+/// in `data`, a file in `format`.
+fn count(data: &[u8], format: ImageFormat) -> usize {
+    let n = match format {
+        ImageFormat::Tiff => tiff_page_count(data),
+        ImageFormat::Ico => ico_entries(data).map(|e| e.len()),
+        ImageFormat::Dds => DdsLayout::parse(data).map(|l| l.count()),
+        _ => None,
+    };
+    n.unwrap_or(1).max(1)
+}
+
+/// Decode the subimage at `index` of `data`, the contents of the file at
+/// `path`, and count the subimages of the file (1 for formats without such a
+/// concept). Index 0 is what `image::open` shows.
+pub fn decode(
+    path: &Path,
+    data: &[u8],
+    index: usize,
+    limits: &DecodeLimits,
+) -> Result<(DynamicImage, usize), DecodeError> {
+    let format = match ImageFormat::from_path(path) {
+        Ok(format) if is_multi_image_format(format) => format,
+        format => return Ok((load_with_limits(data, format.ok(), limits)?, 1)),
+    };
+
+    let image = match format {
+        _ if index == 0 => decode_in_memory(data, format, limits),
+        ImageFormat::Tiff => tiff_page(data, index, limits),
+        ImageFormat::Ico => {
+            let entries = ico_entries(data).ok_or_else(|| subimage_error(format, "bad header"))?;
+            let entry = entries
+                .get(index)
+                .ok_or_else(|| subimage_error(format, "no such icon"))?;
+            let icon = single_icon(data, entry)
+                .ok_or_else(|| subimage_error(format, "icon out of bounds"))?;
+            decode_in_memory(&icon, ImageFormat::Ico, limits)
+        }
+        ImageFormat::Dds => {
+            let layout =
+                DdsLayout::parse(data).ok_or_else(|| subimage_error(format, "unsupported"))?;
+            let dds = layout
+                .single_surface(data, index)
+                .ok_or_else(|| subimage_error(format, "no such surface"))?;
+            decode_in_memory(&dds, ImageFormat::Dds, limits)
+        }
+        _ => unreachable!(),
+    }?;
+    Ok((image, count(data, format)))
+}
+
+fn decode_in_memory(
+    data: &[u8],
+    format: ImageFormat,
+    limits: &DecodeLimits,
+) -> Result<DynamicImage, DecodeError> {
+    let decoder = image::ImageReader::with_format(Cursor::new(data), format).into_decoder()?;
+    decode_with_limits(decoder, limits)
+}
+
+fn tiff_page_count(data: &[u8]) -> Option<usize> {
+    let mut decoder = tiff::decoder::Decoder::new(Cursor::new(data)).ok()?;
+    let mut n = 1;
+    while decoder.more_images() {
+        decoder.next_image().ok()?;
+        n += 1;
+    }
+    Some(n)
+}
+
+fn tiff_page(data: &[u8], page: usize, limits: &DecodeLimits) -> Result<DynamicImage, DecodeError> {
+    use tiff::decoder::DecodingResult;
+    use tiff::{ColorType, TiffError};
+
+    let err = |e: TiffError| match e {
+        TiffError::LimitsExceeded => DecodeError::TooMuchMemory {
+            max_alloc: limits.max_alloc,
+        },
+        e => subimage_error(ImageFormat::Tiff, e).into(),
+    };
+    let mut tiff_limits = tiff::decoder::Limits::default();
+    tiff_limits.decoding_buffer_size = limits.max_alloc.try_into().unwrap_or(usize::MAX);
+    let mut decoder = tiff::decoder::Decoder::new(Cursor::new(data))
+        .map_err(err)?
+        .with_limits(tiff_limits);
+    decoder.seek_to_image(page).map_err(err)?;
+    let (w, h) = decoder.dimensions().map_err(err)?;
+    limits.check(w, h)?;
+    let color = decoder.colortype().map_err(err)?;
+
+    let image = match (color, decoder.read_image().map_err(err)?) {
+        (ColorType::Gray(1), DecodingResult::U8(buf)) => {
+            // Bilevel scans: one bit per pixel, rows padded to the byte. The
+            // decoder already turned WhiteIsZero ones (fax) into BlackIsZero
+            let row_len = (w as usize).div_ceil(8);
+            if buf.len() < row_len * h as usize {
+                return Err(subimage_error(ImageFormat::Tiff, "unexpected buffer size").into());
+            }
+            let pixels = buf
+                .chunks(row_len)
+                .take(h as usize)
+                .flat_map(|row| {
+                    (0..w as usize).map(move |x| {
+                        if row[x / 8] & (0x80 >> (x % 8)) != 0 {
+                            255
+                        } else {
+                            0
+                        }
+                    })
+                })
+                .collect();
+            ImageBuffer::from_raw(w, h, pixels).map(DynamicImage::ImageLuma8)
+        }
+        (ColorType::Gray(8), DecodingResult::U8(buf)) => {
+            ImageBuffer::from_raw(w, h, buf).map(DynamicImage::ImageLuma8)
+        }
+        (ColorType::GrayA(8), DecodingResult::U8(buf)) => {
+            ImageBuffer::from_raw(w, h, buf).map(DynamicImage::ImageLumaA8)
+        }
+        (ColorType::RGB(8), DecodingResult::U8(buf)) => {
+            ImageBuffer::from_raw(w, h, buf).map(DynamicImage::ImageRgb8)
+        }
+        (ColorType::RGBA(8), DecodingResult::U8(buf)) => {
+            ImageBuffer::from_raw(w, h, buf).map(DynamicImage::ImageRgba8)
+        }
+        (ColorType::Gray(16), DecodingResult::U16(buf)) => {
+            ImageBuffer::from_raw(w, h, buf).map(DynamicImage::ImageLuma16)
+        }
+        (ColorType::GrayA(16), DecodingResult::U16(buf)) => {
+            ImageBuffer::from_raw(w, h, buf).map(DynamicImage::ImageLumaA16)
+        }
+        (ColorType::RGB(16), DecodingResult::U16(buf)) => {
+            ImageBuffer::from_raw(w, h, buf).map(DynamicImage::ImageRgb16)
+        }
+        (ColorType::RGBA(16), DecodingResult::U16(buf)) => {
+            ImageBuffer::from_raw(w, h, buf).map(DynamicImage::ImageRgba16)
+        }
+        (ColorType::RGB(32), DecodingResult::F32(buf)) => {
+            ImageBuffer::from_raw(w, h, buf).map(DynamicImage::ImageRgb32F)
+        }
+        (ColorType::RGBA(32), DecodingResult::F32(buf)) => {
+            ImageBuffer::from_raw(w, h, buf).map(DynamicImage::ImageRgba32F)
+        }
+        (color, _) => {
+            return Err(subimage_error(
+                ImageFormat::Tiff,
+                format!("unsupported color type {:?} on page {}", color, page + 1),
+            )
+            .into())
+        }
+    };
+
+    image.ok_or_else(|| subimage_error(ImageFormat::Tiff, "unexpected buffer size").into())
+}
+
+/// Directory entries of an ICO/CUR file, sorted the way the image crate
+/// scores them (deepest, then biggest first), so that entry 0 is its pick.
+fn ico_entries(data: &[u8]) -> Option<Vec<&[u8]>> {
+    let count = u16::from_le_bytes(data.get(4..6)?.try_into().ok()?) as usize;
+    let mut entries: Vec<&[u8]> = (0..count)
+        .map(|n| data.get(6 + n * 16..6 + (n + 1) * 16))
+        .collect::<Option<_>>()?;
+
+    // 0 means 256 for width and height
+    let dim = |b: u8| if b == 0 { 256 } else { b as u32 };
+    let depth = |e: &[u8]| u16::from_le_bytes([e[6], e[7]]);
+    entries.sort_by_key(|e| std::cmp::Reverse((depth(e), dim(e[0]) * dim(e[1]))));
+    Some(entries)
+}
+
+/// Build an ICO file holding only `entry`.
+fn single_icon(data: &[u8], entry: &[u8]) -> Option<Vec<u8>> {
+    let size = u32::from_le_bytes(entry[8..12].try_into().ok()?) as usize;
+    let offset = u32::from_le_bytes(entry[12..16].try_into().ok()?) as usize;
+    let payload = data.get(offset..offset.checked_add(size)?)?;
+
+    let mut icon = Vec::with_capacity(22 + size);
+    icon.extend_from_slice(&data[0..4]);
+    icon.extend_from_slice(&1u16.to_le_bytes());
+    icon.extend_from_slice(&entry[0..12]);
+    icon.extend_from_slice(&22u32.to_le_bytes());
+    icon.extend_from_slice(payload);
+    Some(icon)
+}
+
+const DDS_HEADER_LEN: usize = 4 + 124;
+const DX10_HEADER_LEN: usize = 20;
+
+/// Where the surfaces of a block compressed DDS texture are.
+#[derive(Debug, PartialEq, Eq)]
+struct DdsLayout {
+    header_len: usize,
+    width: u32,
+    height: u32,
+    mips: usize,
+    layers: usize,
+    block_bytes: usize,
+}
+
+impl DdsLayout {
+    fn parse(data: &[u8]) -> Option<DdsLayout> {
+        let u32_at = |at: usize| -> Option<u32> {
+            Some(u32::from_le_bytes(data.get(at..at + 4)?.try_into().ok()?))
+        };
+        if data.get(0..4)? != b"DDS " {
+            return None;
+        }
+
+        let flags = u32_at(8)?;
+        let height = u32_at(12)?;
+        let width = u32_at(16)?;
+        // DDSD_MIPMAPCOUNT
+        let mips = if flags & 0x20000 != 0 {
+            u32_at(28)?.max(1) as usize
+        } else {
+            1
+        };
+        let caps2 = u32_at(112)?;
+        let cube_faces = if caps2 & 0x200 != 0 {
+            (caps2 & 0xFC00).count_ones() as usize
+        } else {
+            1
+        };
+
+        let (header_len, block_bytes, layers) = match data.get(84..88)? {
+            b"DXT1" => (DDS_HEADER_LEN, 8, cube_faces),
+            b"DXT3" | b"DXT5" => (DDS_HEADER_LEN, 16, cube_faces),
+            b"DX10" => {
+                let block_bytes = match u32_at(DDS_HEADER_LEN)? {
+                    70..=72 => 8,
+                    73..=78 => 16,
+                    _ => return None,
+                };
+                let is_cube = u32_at(DDS_HEADER_LEN + 8)? & 0x4 != 0;
+                let array_size = u32_at(DDS_HEADER_LEN + 12)?.max(1) as usize;
+                let layers = array_size * if is_cube { 6 } else { 1 };
+                (DDS_HEADER_LEN + DX10_HEADER_LEN, block_bytes, layers)
+            }
+            _ => return None,
+        };
+
+        Some(DdsLayout {
+            header_len,
+            width,
+            height,
+            mips,
+            layers,
+            block_bytes,
+        })
+    }
+
+    fn count(&self) -> usize {
+        self.mips * self.layers
+    }
+
+    fn mip_size(&self, mip: usize) -> (u32, u32) {
+        ((self.width >> mip).max(1), (self.height >> mip).max(1))
+    }
+
+    fn mip_bytes(&self, mip: usize) -> usize {
+        let (w, h) = self.mip_size(mip);
+        w.div_ceil(4) as usize * h.div_ceil(4) as usize * self.block_bytes
+    }
+
+    /// Build a DDS file holding only surface `index` (layers first, then
+    /// their mip levels, same order as in the file).
+    fn single_surface(&self, data: &[u8], index: usize) -> Option<Vec<u8>> {
+        if index >= self.count() {
+            return None;
+        }
+        let (layer, mip) = (index / self.mips, index % self.mips);
+        let layer_bytes: usize = (0..self.mips).map(|m| self.mip_bytes(m)).sum();
+        let offset = self.header_len
+            + layer * layer_bytes
+            + (0..mip).map(|m| self.mip_bytes(m)).sum::<usize>();
+        let surface = data.get(offset..offset + self.mip_bytes(mip))?;
+
+        let (w, h) = self.mip_size(mip);
+        let mut dds = data[..self.header_len].to_vec();
+        dds[12..16].copy_from_slice(&h.to_le_bytes());
+        dds[16..20].copy_from_slice(&w.to_le_bytes());
+        dds[20..24].copy_from_slice(&(surface.len() as u32).to_le_bytes());
+        dds[28..32].copy_from_slice(&1u32.to_le_bytes());
+        // No cube map faces, a single array element
+        dds[112..116].copy_from_slice(&0u32.to_le_bytes());
+        if self.header_len > DDS_HEADER_LEN {
+            let misc = u32::from_le_bytes(
+                dds[DDS_HEADER_LEN + 8..DDS_HEADER_LEN + 12]
+                    .try_into()
+                    .ok()?,
+            );
+            dds[DDS_HEADER_LEN + 8..DDS_HEADER_LEN + 12]
+                .copy_from_slice(&(misc & !0x4).to_le_bytes());
+            dds[DDS_HEADER_LEN + 12..DDS_HEADER_LEN + 16].copy_from_slice(&1u32.to_le_bytes());
+        }
+        dds.extend_from_slice(surface);
+        Some(dds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 10x2 bilevel TIFF of `rows`, with `photometric` interpretation.
+    fn bilevel_tiff(photometric: u16, rows: [[u8; 2]; 2]) -> Vec<u8> {
+        let mut data = b"II*\0".to_vec();
+        data.extend_from_slice(&8u32.to_le_bytes());
+        let entries: [(u16, u16, u32); 8] = [
+            (256, 3, 10),
+            (257, 3, 2),
+            (258, 3, 1),
+            (259, 3, 1),
+            (262, 3, photometric as u32),
+            (273, 4, 8 + 2 + 8 * 12 + 4),
+            (278, 3, 2),
+            (279, 4, 4),
+        ];
+        data.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        for (tag, kind, value) in entries {
+            data.extend_from_slice(&tag.to_le_bytes());
+            data.extend_from_slice(&kind.to_le_bytes());
+            data.extend_from_slice(&1u32.to_le_bytes());
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend(rows.concat());
+        data
+    }
+
+    #[test]
+    fn test_bilevel_tiff() {
+        let rows = [[0b1000_0000, 0b0100_0000], [0, 0]];
+        let limits = DecodeLimits::default();
+        let black_is_zero = tiff_page(&bilevel_tiff(1, rows), 0, &limits).unwrap();
+        let white_is_zero = tiff_page(&bilevel_tiff(0, rows), 0, &limits).unwrap();
+        let pixels = |image: &DynamicImage| {
+            let image = image.to_luma8();
+            [(0, 0), (1, 0), (9, 0), (0, 1)].map(|(x, y)| image.get_pixel(x, y).0[0])
+        };
+        assert_eq!([255, 0, 255, 0], pixels(&black_is_zero));
+        // Fax scans: ink where bits are set
+        assert_eq!([0, 255, 0, 255], pixels(&white_is_zero));
+    }
+
+    #[test]
+    fn test_dds_layout() {
+        // DXT1 128x64, 8 mip levels, 2 array layers
+        let mut data = vec![0u8; DDS_HEADER_LEN + DX10_HEADER_LEN];
+        data[0..4].copy_from_slice(b"DDS ");
+        data[8..12].copy_from_slice(&0x20000u32.to_le_bytes());
+        data[12..16].copy_from_slice(&64u32.to_le_bytes());
+        data[16..20].copy_from_slice(&128u32.to_le_bytes());
+        data[28..32].copy_from_slice(&8u32.to_le_bytes());
+        data[84..88].copy_from_slice(b"DX10");
+        data[128..132].copy_from_slice(&71u32.to_le_bytes());
+        data[140..144].copy_from_slice(&2u32.to_le_bytes());
+
+        let layout = DdsLayout::parse(&data).unwrap();
+        assert_eq!(16, layout.count());
+        assert_eq!((16, 8), layout.mip_size(3));
+        // 32x16 blocks of 8 bytes
+        assert_eq!(4096, layout.mip_bytes(0));
+        // 1x1 still takes a whole block
+        assert_eq!(8, layout.mip_bytes(7));
+
+        let layer_bytes: usize = (0..8).map(|m| layout.mip_bytes(m)).sum();
+        data.resize(data.len() + 2 * layer_bytes, 0);
+        let surface = layout.single_surface(&data, 9).unwrap();
+        assert_eq!(DDS_HEADER_LEN + DX10_HEADER_LEN + 1024, surface.len());
+        assert_eq!(None, layout.single_surface(&data, 16));
+    }
+}
@@ -0,0 +1,149 @@
+use image::error::{DecodingError, ImageFormatHint};
+use image::{DynamicImage, ImageError, RgbaImage};
+use resvg::{tiny_skia, usvg};
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+
+/// Largest raster we are willing to produce, same budget as `clamp_image_size`.
+const MAX_PIXELS: u64 = 134_217_728 / 4;
+
+/// System fonts for the text of documents, scanned once as it is slow.
+static FONTS: OnceLock<Arc<usvg::fontdb::Database>> = OnceLock::new();
+
+fn fonts() -> Arc<usvg::fontdb::Database> {
+    FONTS.get_or_init(|| Arc::new(system_fonts())).clone()
+}
+
+fn system_fonts() -> usvg::fontdb::Database {
+    let mut fonts = usvg::fontdb::Database::new();
+    fonts.load_system_fonts();
+    fonts
+}
+
+/// Map the font files in memory right away, for a process that won't be
+/// allowed to open them when rendering (see `sandbox`).
+pub fn preload_fonts() {
+    FONTS.get_or_init(|| {
+        let mut fonts = system_fonts();
+        let ids: Vec<_> = fonts.faces().map(|face| face.id).collect();
+        for id in ids {
+            // SAFETY: the same mapping fontdb makes whenever it reads a face,
+            // trusting the files not to be truncated meanwhile
+            unsafe { fonts.make_shared_face_data(id) };
+        }
+        Arc::new(fonts)
+    });
+}
+
+pub fn is_svg(path: impl AsRef<Path>) -> bool {
+    path.as_ref()
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.eq_ignore_ascii_case("svg") || ext.eq_ignore_ascii_case("svgz"))
+        .unwrap_or(false)
+}
+
+fn svg_error(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> ImageError {
+    ImageError::Decoding(DecodingError::new(
+        ImageFormatHint::Name("SVG".to_string()),
+        e,
+    ))
+}
+
+/// Rasterize `data`, the SVG (or gzipped SVGZ) file at `path`, so that it
+/// fits in `max_w`x`max_h`, preserving the aspect ratio of the document.
+pub fn rasterize(
+    path: &Path,
+    data: &[u8],
+    max_w: u32,
+    max_h: u32,
+) -> Result<DynamicImage, ImageError> {
+    let opt = usvg::Options {
+        resources_dir: path.parent().map(|p| p.to_owned()),
+        fontdb: fonts(),
+        ..Default::default()
+    };
+
+    // usvg takes care of decompressing .svgz
+    let tree = usvg::Tree::from_data(data, &opt).map_err(svg_error)?;
+
+    let (w, h, scale) = raster_size(tree.size().width(), tree.size().height(), max_w, max_h);
+    let mut pixmap = tiny_skia::Pixmap::new(w, h).ok_or_else(|| svg_error("invalid size"))?;
+    resvg::render(
+        &tree,
+        tiny_skia::Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+
+    // tiny-skia works with premultiplied alpha
+    let mut buffer = Vec::with_capacity(w as usize * h as usize * 4);
+    for pixel in pixmap.pixels() {
+        let c = pixel.demultiply();
+        buffer.extend_from_slice(&[c.red(), c.green(), c.blue(), c.alpha()]);
+    }
+
+    RgbaImage::from_raw(w, h, buffer)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| svg_error("pixmap size mismatch"))
+}
+
+/// Compute the raster size (and the scale to apply to the document) to fit
+/// a `doc_w`x`doc_h` document in `max_w`x`max_h`, within the pixel budget.
+fn raster_size(doc_w: f32, doc_h: f32, max_w: u32, max_h: u32) -> (u32, u32, f32) {
+    let mut scale = (max_w.max(1) as f32 / doc_w).min(max_h.max(1) as f32 / doc_h);
+
+    let pixels = (doc_w * scale) as u64 * (doc_h * scale) as u64;
+    // Rounded down when over the budget, not to go past it again
+    let round = if pixels > MAX_PIXELS {
+        scale *= ((MAX_PIXELS as f64) / (pixels as f64)).sqrt() as f32;
+        f32::floor
+    } else {
+        f32::round
+    };
+
+    let w = (round(doc_w * scale) as u32).max(1);
+    let h = (round(doc_h * scale) as u32).max(1);
+    (w, h, scale)
+}
+
+/// Whether an already rasterized `current` image is too blurry (or needlessly
+/// large) to be displayed in a `target` sized area.
+pub fn needs_rerasterize(current: (u32, u32), target: (u32, u32)) -> bool {
+    let (cw, ch) = (current.0.max(1) as f64, current.1.max(1) as f64);
+    // The raster keeps the document aspect ratio, so this is the scale the
+    // next render would have relative to the current one.
+    let ratio = (target.0 as f64 / cw)
+        .min(target.1 as f64 / ch)
+        .min((MAX_PIXELS as f64 / (cw * ch)).sqrt());
+    // Hysteresis, so that every tiny zoom step doesn't cause a new render
+    !(0.5..=1.25).contains(&ratio)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_raster_size() {
+        // Fits the document in the box, keeping its aspect ratio
+        assert_eq!((200, 100, 2.0), raster_size(100.0, 50.0, 400, 100));
+        assert_eq!((400, 200, 4.0), raster_size(100.0, 50.0, 400, 400));
+        // Within the pixel budget
+        let (w, h, _) = raster_size(100.0, 100.0, 100_000, 100_000);
+        assert!(w as u64 * h as u64 <= MAX_PIXELS);
+        assert_eq!(w, h);
+    }
+
+    #[test]
+    fn test_needs_rerasterize() {
+        assert!(!needs_rerasterize((400, 200), (400, 200)));
+        // Small zoom steps don't render again
+        assert!(!needs_rerasterize((400, 200), (480, 240)));
+        assert!(!needs_rerasterize((400, 200), (220, 110)));
+        assert!(needs_rerasterize((400, 200), (800, 400)));
+        assert!(needs_rerasterize((400, 200), (100, 50)));
+        // Not past the pixel budget
+        let side = (MAX_PIXELS as f64).sqrt() as u32;
+        assert!(!needs_rerasterize((side, side), (side * 4, side * 4)));
+    }
+}
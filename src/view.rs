@@ -0,0 +1,595 @@
+use show_image::glam::{Affine2, Mat2, Vec2};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Zoom steps are the powers of this (2^(1/4)) times 100%, so that they hit
+/// 50%, 100%, 200%...
+const ZOOM_STEP: f32 = 1.189_207_1;
+/// How far one can zoom in at least (3200%). More for images so small that
+/// this wouldn't even fill the window.
+const MAX_ZOOM: f32 = 32.0;
+
+pub enum Rotation {
+    Right,
+    Left,
+}
+
+/// Axis of a mirroring, as seen on screen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flip {
+    /// Left and right swapped
+    Horizontal,
+    /// Top and bottom swapped
+    Vertical,
+}
+
+/// How to size the image relative to the window.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FitMode {
+    /// Whole image visible, as large as possible
+    Fit,
+    /// Window covered, cropping what doesn't fit
+    Fill,
+    /// One image pixel per screen pixel
+    Actual,
+    FitWidth,
+    FitHeight,
+    /// This percentage of the image pixels
+    Percent(f32),
+}
+
+impl FitMode {
+    pub fn from_name(name: &str) -> Option<FitMode> {
+        match name {
+            "fit" => Some(FitMode::Fit),
+            "fill" => Some(FitMode::Fill),
+            "actual" => Some(FitMode::Actual),
+            "width" => Some(FitMode::FitWidth),
+            "height" => Some(FitMode::FitHeight),
+            _ => None,
+        }
+    }
+
+    /// Zoom for an image taking `size` on screen at 100%.
+    fn zoom(&self, size: Vec2, window: Vec2) -> f32 {
+        let ratios = size / window;
+        match self {
+            FitMode::Fit => 1.0 / ratios.max_element(),
+            FitMode::Fill => 1.0 / ratios.min_element(),
+            FitMode::Actual => 1.0,
+            FitMode::FitWidth => 1.0 / ratios.x,
+            FitMode::FitHeight => 1.0 / ratios.y,
+            FitMode::Percent(percent) => percent / 100.0,
+        }
+    }
+}
+
+/// What happens to the view when another image is shown.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ViewPolicy {
+    /// Start over, sized by the fit mode
+    Reset,
+    /// Same zoom, pan and orientation as the previous image
+    Keep,
+    /// Back to how each image was last seen
+    Remember,
+}
+
+impl ViewPolicy {
+    pub fn from_name(name: &str) -> Option<ViewPolicy> {
+        match name {
+            "reset" => Some(ViewPolicy::Reset),
+            "keep" => Some(ViewPolicy::Keep),
+            "remember" => Some(ViewPolicy::Remember),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ViewPolicy::Reset => "reset",
+            ViewPolicy::Keep => "keep",
+            ViewPolicy::Remember => "remember",
+        }
+    }
+
+    /// The policy after this one, to cycle through them.
+    pub fn next(&self) -> ViewPolicy {
+        match self {
+            ViewPolicy::Reset => ViewPolicy::Keep,
+            ViewPolicy::Keep => ViewPolicy::Remember,
+            ViewPolicy::Remember => ViewPolicy::Reset,
+        }
+    }
+}
+
+/// Views of the images left behind, to apply `policy` to the next ones.
+///
+/// Images are told apart by path and subimage. Views are stored with the
+/// size of the image they were for, so that they still show the same part
+/// of an image rendered again at another size (SVG), or of a similar one.
+pub struct ViewMemory {
+    pub policy: ViewPolicy,
+    /// The image currently shown, if its view is worth keeping.
+    shown: Option<(PathBuf, usize)>,
+    last: Option<(ViewState, Vec2)>,
+    per_image: HashMap<(PathBuf, usize), (ViewState, Vec2)>,
+}
+
+impl ViewMemory {
+    pub fn new(policy: ViewPolicy) -> Self {
+        ViewMemory {
+            policy,
+            shown: None,
+            last: None,
+            per_image: HashMap::new(),
+        }
+    }
+
+    /// Record `view` of the image being shown, of size `image`, as it is
+    /// replaced.
+    pub fn leave(&mut self, view: ViewState, image: Vec2) {
+        if let Some(key) = self.shown.take() {
+            self.per_image.insert(key, (view, image));
+            self.last = Some((view, image));
+        }
+    }
+
+    /// Something that isn't an image (e.g. an error) is shown.
+    pub fn forget_shown(&mut self) {
+        self.shown = None;
+    }
+
+    /// The view to show subimage `index` of `path` (of size `image`) with,
+    /// or None to start over.
+    pub fn enter(&mut self, path: &Path, index: usize, image: Vec2) -> Option<ViewState> {
+        let key = (path.to_owned(), index);
+        let previous = match self.policy {
+            ViewPolicy::Reset => None,
+            ViewPolicy::Keep => self.last,
+            ViewPolicy::Remember => self.per_image.get(&key).copied(),
+        };
+        self.shown = Some(key);
+        previous.map(|(mut view, size)| {
+            // Same part of the image: the zoom follows the width
+            view.zoom *= size.x / image.x;
+            view
+        })
+    }
+}
+
+/// What part of the image is shown and how, independently of any window.
+///
+/// Sizes given to the methods are in pixels: `image` is the size of the
+/// displayed image, `window` the inner size of the window.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ViewState {
+    /// Window pixels per image pixel: 1.0 shows the image at 100%.
+    pub zoom: f32,
+    /// Point of the image at the center of the window, from (0, 0) at the top
+    /// left corner to (1, 1) at the bottom right one.
+    pub center: Vec2,
+    /// Clockwise quarter turns, 0 to 3.
+    pub quarter_turns: u8,
+    /// Clockwise degrees on top of the quarter turns, within ±45.
+    pub angle: f32,
+    /// Mirrored left to right, before rotating.
+    pub flip_horizontal: bool,
+    /// Mirrored top to bottom, before rotating.
+    pub flip_vertical: bool,
+}
+
+impl Default for ViewState {
+    fn default() -> Self {
+        ViewState {
+            zoom: 1.0,
+            center: Vec2::splat(0.5),
+            quarter_turns: 0,
+            angle: 0.0,
+            flip_horizontal: false,
+            flip_vertical: false,
+        }
+    }
+}
+
+impl ViewState {
+    /// Orientation of the image on screen (flips, then rotation), in a
+    /// y-down coordinate system.
+    pub fn orientation(&self) -> Mat2 {
+        let rotation = match self.quarter_turns % 4 {
+            0 => Mat2::IDENTITY,
+            1 => Mat2::from_cols(Vec2::Y, Vec2::NEG_X),
+            2 => Mat2::from_cols(Vec2::NEG_X, Vec2::NEG_Y),
+            _ => Mat2::from_cols(Vec2::NEG_Y, Vec2::X),
+        };
+        let flip = Mat2::from_diagonal(Vec2::new(
+            if self.flip_horizontal { -1.0 } else { 1.0 },
+            if self.flip_vertical { -1.0 } else { 1.0 },
+        ));
+        Mat2::from_angle(self.angle.to_radians()) * rotation * flip
+    }
+
+    /// Size of the image at 100% once turned by the quarter turns only.
+    pub fn upright_size(&self, image: Vec2) -> Vec2 {
+        if self.quarter_turns % 2 == 1 {
+            Vec2::new(image.y, image.x)
+        } else {
+            image
+        }
+    }
+
+    /// Size on screen at 100% of the box around the rotated image.
+    fn oriented_size(&self, image: Vec2) -> Vec2 {
+        let size = self.upright_size(image);
+        if self.angle == 0.0 {
+            return size;
+        }
+        let (sin, cos) = self.angle.to_radians().sin_cos();
+        let (sin, cos) = (sin.abs(), cos.abs());
+        Vec2::new(size.x * cos + size.y * sin, size.x * sin + size.y * cos)
+    }
+
+    /// The show-image transform, mapping the image (as the unit square) to
+    /// normalized window coordinates. To use without preserving the aspect
+    /// ratio, as it is taken care of here.
+    pub fn transform(&self, image: Vec2, window: Vec2) -> Affine2 {
+        let to_window = Mat2::from_diagonal(self.zoom / window)
+            * self.orientation()
+            * Mat2::from_diagonal(image);
+        Affine2::from_mat2_translation(to_window, Vec2::splat(0.5) - to_window * self.center)
+    }
+
+    /// Image point (normalized) shown at `position` (window pixels).
+    pub fn image_point(&self, position: Vec2, image: Vec2, window: Vec2) -> Vec2 {
+        let offset = (position - window / 2.0) / self.zoom;
+        self.center + self.orientation().transpose() * offset / image
+    }
+
+    /// Smallest and largest corners (image pixels) of the part of the image
+    /// seen in the window.
+    pub fn visible_part(&self, image: Vec2, window: Vec2) -> (Vec2, Vec2) {
+        let corners = [
+            Vec2::ZERO,
+            Vec2::new(window.x, 0.0),
+            Vec2::new(0.0, window.y),
+            window,
+        ]
+        .map(|corner| self.image_point(corner, image, window) * image);
+        let min = corners.into_iter().reduce(Vec2::min).unwrap_or_default();
+        let max = corners.into_iter().reduce(Vec2::max).unwrap_or_default();
+        (min.max(Vec2::ZERO), max.min(image))
+    }
+
+    /// Size the image according to `mode` and center it, keeping orientation.
+    pub fn fit(&mut self, mode: FitMode, image: Vec2, window: Vec2) {
+        self.zoom = mode.zoom(self.oriented_size(image), window);
+        self.center = Vec2::splat(0.5);
+    }
+
+    /// Smallest and largest zoom allowed.
+    pub fn zoom_limits(&self, image: Vec2, window: Vec2) -> (f32, f32) {
+        let fit = FitMode::Fit.zoom(self.oriented_size(image), window);
+        (fit.min(1.0) / 4.0, MAX_ZOOM.max(fit * 4.0))
+    }
+
+    /// Zoom to `zoom` (within limits), keeping still the image point under
+    /// `anchor` (window pixels), or else the one at the window center.
+    pub fn zoom_to(&mut self, zoom: f32, anchor: Option<Vec2>, image: Vec2, window: Vec2) {
+        let (min, max) = self.zoom_limits(image, window);
+        let anchor = anchor.unwrap_or(window / 2.0);
+        let point = self.image_point(anchor, image, window);
+
+        self.zoom = zoom.clamp(min, max);
+        let offset = (anchor - window / 2.0) / self.zoom;
+        self.center = point - self.orientation().transpose() * offset / image;
+        self.clamp_pan(image, window);
+    }
+
+    /// Go to the next zoom step.
+    pub fn step_zoom(&mut self, zoom_in: bool, image: Vec2, window: Vec2) {
+        self.zoom_to(zoom_step(self.zoom, zoom_in), None, image, window);
+    }
+
+    /// Move the image by `delta` window pixels.
+    pub fn pan(&mut self, delta: Vec2, image: Vec2, window: Vec2) {
+        self.center -= self.orientation().transpose() * delta / self.zoom / image;
+        self.clamp_pan(image, window);
+    }
+
+    /// Keep the image centered on the axes where it fits in the window, and
+    /// its edges out of the window on the others.
+    pub fn clamp_pan(&mut self, image: Vec2, window: Vec2) {
+        let size = self.oriented_size(image) * self.zoom;
+        // Where the image center is, relative to the window center
+        let offset = self.orientation() * ((Vec2::splat(0.5) - self.center) * image) * self.zoom;
+        let room = ((size - window) / 2.0).max(Vec2::ZERO);
+        let offset = offset.clamp(-room, room);
+        self.center =
+            Vec2::splat(0.5) - self.orientation().transpose() * (offset / self.zoom) / image;
+    }
+
+    /// Turn the image a quarter, around the window center.
+    pub fn rotate(&mut self, direction: Rotation, image: Vec2, window: Vec2) {
+        self.quarter_turns = match direction {
+            Rotation::Right => (self.quarter_turns + 1) % 4,
+            Rotation::Left => (self.quarter_turns + 3) % 4,
+        };
+        self.clamp_pan(image, window);
+    }
+
+    /// Total clockwise rotation in degrees, from 0 to 360 (excluded).
+    pub fn rotation(&self) -> f32 {
+        round_angle((self.quarter_turns as f32 * 90.0 + self.angle).rem_euclid(360.0))
+    }
+
+    /// Rotate to `degrees` clockwise, around the window center.
+    pub fn set_rotation(&mut self, degrees: f32, image: Vec2, window: Vec2) {
+        let degrees = round_angle(degrees).rem_euclid(360.0);
+        let turns = (degrees / 90.0).round();
+        self.quarter_turns = turns as u8 % 4;
+        self.angle = round_angle(degrees - turns * 90.0);
+        self.clamp_pan(image, window);
+    }
+
+    /// Rotate by `degrees` clockwise (or counter-clockwise if negative).
+    pub fn rotate_by(&mut self, degrees: f32, image: Vec2, window: Vec2) {
+        self.set_rotation(self.rotation() + degrees, image, window);
+    }
+
+    /// Mirror the image as currently seen on screen, around the window center.
+    pub fn flip(&mut self, flip: Flip, image: Vec2, window: Vec2) {
+        // Once turned a quarter, the screen axes are the other image axes
+        let horizontal = (flip == Flip::Horizontal) != (self.quarter_turns % 2 == 1);
+        if horizontal {
+            self.flip_horizontal = !self.flip_horizontal;
+        } else {
+            self.flip_vertical = !self.flip_vertical;
+        }
+        // A mirrored tilt leans the other way
+        self.angle = -self.angle;
+        self.clamp_pan(image, window);
+    }
+
+    /// The rotation, for humans, when not a multiple of a quarter turn.
+    pub fn angle_description(&self) -> Option<String> {
+        (self.angle != 0.0).then(|| format!("rotated {}°", self.rotation()))
+    }
+
+    /// How the image is mirrored, for humans.
+    pub fn flip_description(&self) -> Option<&'static str> {
+        match (self.flip_horizontal, self.flip_vertical) {
+            (true, true) => Some("flipped both ways"),
+            (true, false) => Some("flipped horizontally"),
+            (false, true) => Some("flipped vertically"),
+            (false, false) => None,
+        }
+    }
+}
+
+/// Angles are kept to a hundredth of a degree, so that repeated small steps
+/// add up to round numbers.
+fn round_angle(degrees: f32) -> f32 {
+    (degrees * 100.0).round() / 100.0
+}
+
+/// The zoom step after `zoom`, going in or out.
+fn zoom_step(zoom: f32, zoom_in: bool) -> f32 {
+    // Close enough to a step counts as being on it
+    let step = zoom.log(ZOOM_STEP);
+    let step = if zoom_in {
+        (step + 0.01).floor() + 1.0
+    } else {
+        (step - 0.01).ceil() - 1.0
+    };
+    ZOOM_STEP.powf(step)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IMAGE: Vec2 = Vec2::new(400.0, 200.0);
+    const WINDOW: Vec2 = Vec2::new(800.0, 600.0);
+
+    fn assert_near(expected: Vec2, actual: Vec2) {
+        assert!(
+            expected.abs_diff_eq(actual, 1e-3),
+            "expected {expected}, got {actual}"
+        );
+    }
+
+    /// Where an image point (normalized) ends up, in window pixels.
+    fn on_screen(view: &ViewState, point: Vec2) -> Vec2 {
+        view.transform(IMAGE, WINDOW).transform_point2(point) * WINDOW
+    }
+
+    #[test]
+    fn test_fit() {
+        let mut view = ViewState::default();
+        view.fit(FitMode::Fit, IMAGE, WINDOW);
+        assert_eq!(2.0, view.zoom);
+        assert_near(Vec2::new(0.0, 100.0), on_screen(&view, Vec2::ZERO));
+        assert_near(Vec2::new(800.0, 500.0), on_screen(&view, Vec2::ONE));
+
+        view.fit(FitMode::Fill, IMAGE, WINDOW);
+        assert_eq!(3.0, view.zoom);
+
+        // Once turned, the image is 200 pixels wide on screen
+        view.rotate(Rotation::Right, IMAGE, WINDOW);
+        view.fit(FitMode::FitWidth, IMAGE, WINDOW);
+        assert_eq!(4.0, view.zoom);
+        view.fit(FitMode::Fit, IMAGE, WINDOW);
+        assert_eq!(1.5, view.zoom);
+    }
+
+    #[test]
+    fn test_zoom_keeps_anchor() {
+        let mut view = ViewState::default();
+        view.fit(FitMode::Fit, IMAGE, WINDOW);
+        view.rotate(Rotation::Left, IMAGE, WINDOW);
+        view.flip_horizontal = true;
+
+        let anchor = Vec2::new(500.0, 250.0);
+        let point = view.image_point(anchor, IMAGE, WINDOW);
+        view.zoom_to(8.0, Some(anchor), IMAGE, WINDOW);
+        assert_eq!(8.0, view.zoom);
+        assert_near(anchor, on_screen(&view, point));
+
+        // Zooming out recenters what fits in the window
+        view.zoom_to(0.5, Some(anchor), IMAGE, WINDOW);
+        assert_near(Vec2::splat(0.5), view.center);
+    }
+
+    #[test]
+    fn test_rotate_keeps_zoom_and_pan() {
+        let mut view = ViewState::default();
+        view.zoom_to(8.0, None, IMAGE, WINDOW);
+        view.pan(Vec2::new(100.0, 50.0), IMAGE, WINDOW);
+        let before = view;
+
+        view.rotate(Rotation::Right, IMAGE, WINDOW);
+        assert_eq!(1, view.quarter_turns);
+        assert_eq!(before.zoom, view.zoom);
+        assert_near(before.center, view.center);
+        // The top left corner is now on the right
+        let corner = on_screen(&view, Vec2::ZERO);
+        assert!(corner.x > WINDOW.x / 2.0 && corner.y < WINDOW.y / 2.0);
+
+        for _ in 0..3 {
+            view.rotate(Rotation::Right, IMAGE, WINDOW);
+        }
+        assert_eq!(0, view.quarter_turns);
+        assert_near(before.center, view.center);
+    }
+
+    #[test]
+    fn test_flip() {
+        let mut view = ViewState::default();
+        view.zoom_to(4.0, None, IMAGE, WINDOW);
+        view.pan(Vec2::new(100.0, 50.0), IMAGE, WINDOW);
+        let center = view.center;
+        let point = view.image_point(Vec2::new(300.0, 200.0), IMAGE, WINDOW);
+
+        // Mirrored around the window center, which doesn't move
+        view.flip(Flip::Horizontal, IMAGE, WINDOW);
+        assert!(view.flip_horizontal);
+        assert_near(center, view.center);
+        assert_near(Vec2::new(500.0, 200.0), on_screen(&view, point));
+
+        // Once turned, what is horizontal on screen is vertical in the image
+        view.flip(Flip::Horizontal, IMAGE, WINDOW);
+        view.rotate(Rotation::Right, IMAGE, WINDOW);
+        view.flip(Flip::Horizontal, IMAGE, WINDOW);
+        assert!(!view.flip_horizontal && view.flip_vertical);
+        let corner = on_screen(&view, Vec2::ZERO);
+        assert!(corner.x < WINDOW.x / 2.0 && corner.y < WINDOW.y / 2.0);
+    }
+
+    #[test]
+    fn test_fine_rotation() {
+        let mut view = ViewState::default();
+        view.set_rotation(91.5, IMAGE, WINDOW);
+        assert_eq!((1, 1.5), (view.quarter_turns, view.angle));
+        for _ in 0..30 {
+            view.rotate_by(-0.1, IMAGE, WINDOW);
+        }
+        assert_eq!((1, -1.5), (view.quarter_turns, view.angle));
+        assert_eq!(88.5, view.rotation());
+        view.rotate_by(-50.0, IMAGE, WINDOW);
+        assert_eq!((0, 38.5), (view.quarter_turns, view.angle));
+        view.set_rotation(-1.0, IMAGE, WINDOW);
+        assert_eq!((0, -1.0), (view.quarter_turns, view.angle));
+        assert_eq!(Some("rotated 359°".to_string()), view.angle_description());
+
+        // Fitting takes the box around the tilted image
+        view.set_rotation(45.0, Vec2::splat(100.0), Vec2::splat(100.0));
+        view.fit(FitMode::Fit, Vec2::splat(100.0), Vec2::splat(100.0));
+        assert!((view.zoom - 0.5f32.sqrt()).abs() < 1e-4);
+
+        // Mirroring keeps what is seen on screen, mirrored
+        let mut view = ViewState::default();
+        view.zoom_to(4.0, None, IMAGE, WINDOW);
+        view.set_rotation(10.0, IMAGE, WINDOW);
+        let point = view.image_point(Vec2::new(300.0, 200.0), IMAGE, WINDOW);
+        view.flip(Flip::Horizontal, IMAGE, WINDOW);
+        assert_eq!(-10.0, view.angle);
+        assert_near(Vec2::new(500.0, 200.0), on_screen(&view, point));
+    }
+
+    #[test]
+    fn test_view_memory() {
+        let (a, b) = (Path::new("a.png"), Path::new("b.png"));
+        let zoomed = ViewState {
+            zoom: 4.0,
+            center: Vec2::new(0.2, 0.3),
+            ..Default::default()
+        };
+
+        let mut memory = ViewMemory::new(ViewPolicy::Reset);
+        assert_eq!(None, memory.enter(a, 0, IMAGE));
+        memory.leave(zoomed, IMAGE);
+        assert_eq!(None, memory.enter(b, 0, IMAGE));
+
+        // Kept relative to the image, even if half as large
+        memory.policy = ViewPolicy::Keep;
+        memory.leave(zoomed, IMAGE);
+        let kept = memory.enter(a, 0, IMAGE / 2.0).unwrap();
+        assert_eq!(8.0, kept.zoom);
+        assert_eq!(zoomed.center, kept.center);
+
+        // Each image as it was left, pages apart
+        memory.policy = ViewPolicy::Remember;
+        memory.leave(ViewState::default(), IMAGE / 2.0);
+        assert_eq!(Some(zoomed), memory.enter(b, 0, IMAGE));
+        memory.leave(zoomed, IMAGE);
+        assert_eq!(Some(ViewState::default()), memory.enter(a, 0, IMAGE / 2.0));
+        memory.forget_shown();
+        memory.leave(ViewState::default(), IMAGE);
+        assert_eq!(Some(zoomed), memory.enter(b, 0, IMAGE));
+        assert_eq!(None, memory.enter(b, 1, IMAGE));
+    }
+
+    #[test]
+    fn test_pan_stays_within_image() {
+        let mut view = ViewState::default();
+        view.fit(FitMode::Fit, IMAGE, WINDOW);
+        view.pan(Vec2::new(100.0, 100.0), IMAGE, WINDOW);
+        assert_near(Vec2::splat(0.5), view.center);
+
+        view.zoom_to(4.0, None, IMAGE, WINDOW);
+        view.pan(Vec2::new(10_000.0, -50.0), IMAGE, WINDOW);
+        // Left edge on the left of the window, moved up by 50 pixels
+        assert_near(Vec2::ZERO, on_screen(&view, Vec2::ZERO) * Vec2::X);
+        assert_near(Vec2::new(0.25, 0.5 + 50.0 / 4.0 / IMAGE.y), view.center);
+    }
+
+    #[test]
+    fn test_zoom_step() {
+        assert!((zoom_step(1.0, true) - ZOOM_STEP).abs() < 1e-4);
+        assert!((zoom_step(1.1, false) - 1.0).abs() < 1e-4);
+        let mut zoom = 1.0;
+        for _ in 0..4 {
+            zoom = zoom_step(zoom, true);
+        }
+        assert!((zoom - 2.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_visible_part() {
+        let mut view = ViewState {
+            zoom: 4.0,
+            ..Default::default()
+        };
+        // 200x150 image pixels around the center
+        let (min, max) = view.visible_part(IMAGE, WINDOW);
+        assert_near(Vec2::new(100.0, 25.0), min);
+        assert_near(Vec2::new(300.0, 175.0), max);
+
+        // Sideways, within the image
+        view.quarter_turns = 1;
+        view.zoom = 2.0;
+        let (min, max) = view.visible_part(IMAGE, WINDOW);
+        assert_near(Vec2::new(50.0, 0.0), min);
+        assert_near(Vec2::new(350.0, 200.0), max);
+    }
+}
@@ -1,79 +1,247 @@
 use anyhow::{anyhow, Result};
-use show_image::glam;
+use image::imageops::FilterType;
+use image::{DynamicImage, Rgba, RgbaImage};
+use show_image::{glam, AsImageView};
+use std::cell::{Cell, RefCell};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
+use crate::background::{Background, BackgroundMode};
+use crate::compare::{CompareMode, Comparison, Frame};
+use crate::diff::DiffOptions;
 use crate::fs_utils::ImagePair;
+use crate::view::{FitMode, Flip, Rotation, ViewState};
+use crate::{filmstrip, hud, inspect, placeholder, straighten};
 
-pub struct Window {
-    pub window: show_image::WindowProxy,
-}
+/// Name of the show-image overlay holding the straightening grid.
+const GRID_OVERLAY: &str = "grid";
+/// Name of the show-image overlay with the strip of neighboring images.
+const FILMSTRIP_OVERLAY: &str = "filmstrip";
+/// Name of the show-image overlay with information about the image.
+const HUD_OVERLAY: &str = "info";
+/// Zoom from which image pixels are outlined, and so shown as sharp squares.
+const PIXEL_GRID_ZOOM: f32 = 8.0;
+const PIXEL_GRID_COLOR: Rgba<u8> = Rgba([128, 128, 128, 140]);
+/// Longest side of the textures made to show pixels magnified or outlined.
+/// Only the part of the image in view is magnified, so that this is enough
+/// at any image size.
+const MAX_MAGNIFIED_SIDE: u32 = 4096;
+/// Time without changes to the view after which the overlays placed over
+/// the image are drawn again.
+const OVERLAY_DELAY: Duration = Duration::from_millis(150);
 
-pub enum Rotation {
-    Right,
-    Left,
+/// Part of the image on screen, from `min` to `max` (image pixels), each
+/// pixel a square of `scale` texels, outlined or not.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Texture {
+    min: glam::UVec2,
+    max: glam::UVec2,
+    scale: u32,
+    outline: bool,
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub enum RotationState {
-    UP,
-    RIGHT,
-    DOWN,
-    LEFT,
-}
-impl RotationState {
-    fn clockwise(&self) -> RotationState {
-        match self {
-            RotationState::UP => RotationState::RIGHT,
-            RotationState::RIGHT => RotationState::DOWN,
-            RotationState::DOWN => RotationState::LEFT,
-            RotationState::LEFT => RotationState::UP,
+impl Texture {
+    /// The image of `size` as is.
+    fn whole(size: glam::Vec2) -> Self {
+        Texture {
+            min: glam::UVec2::ZERO,
+            max: size.as_uvec2(),
+            scale: 1,
+            outline: false,
         }
     }
 
-    fn counter_clockwise(&self) -> RotationState {
-        match self {
-            RotationState::UP => RotationState::LEFT,
-            RotationState::RIGHT => RotationState::UP,
-            RotationState::DOWN => RotationState::RIGHT,
-            RotationState::LEFT => RotationState::DOWN,
-        }
+    /// Where the texture is in an image of `size`, both as the unit square.
+    fn placement(&self, size: glam::Vec2) -> glam::Affine2 {
+        glam::Affine2::from_scale_angle_translation(
+            (self.max - self.min).as_vec2() / size,
+            0.0,
+            self.min.as_vec2() / size,
+        )
     }
 
-    fn rotate(&self, direction: Rotation) -> RotationState {
-        match direction {
-            Rotation::Right => self.clockwise(),
-            Rotation::Left => self.counter_clockwise(),
-        }
+    fn contains(&self, (min, max): (glam::Vec2, glam::Vec2)) -> bool {
+        self.min.as_vec2().cmple(min).all() && self.max.as_vec2().cmpge(max).all()
     }
 }
 
+pub struct Window {
+    pub window: show_image::WindowProxy,
+    /// Title describing the current image, see `show_prompt`.
+    title: RefCell<String>,
+    /// Name the image was put on screen with, see `show`.
+    name: RefCell<String>,
+    /// What the transform of the window is made of.
+    view: Cell<ViewState>,
+    /// Whether the straightening grid is shown.
+    grid: Cell<bool>,
+    /// Size in pixels of the image shown, which the view is about. The
+    /// texture on screen may be a magnified copy.
+    size: Cell<Option<glam::Vec2>>,
+    /// The image shown as it was given, to inspect its pixels, magnify it
+    /// or put it over another background.
+    source: RefCell<Option<DynamicImage>>,
+    /// What of the image is on screen, and how magnified.
+    texture: Cell<Texture>,
+    /// Zoom from which pixels are shown as sharp squares, if any.
+    nearest_above: Cell<Option<f32>>,
+    /// Show sharp pixels at any zoom.
+    nearest_forced: Cell<bool>,
+    /// Shared with whatever else puts images in the window.
+    background: Arc<RwLock<Background>>,
+    /// What the on-screen information says about the image, see `set_info`.
+    info: RefCell<Vec<String>>,
+    /// Whether the on-screen information is shown.
+    hud: Cell<bool>,
+    /// What the pixel inspector tells, see `show_inspection`.
+    inspection: RefCell<Option<String>>,
+    /// Strip of thumbnails shown along the bottom, see `show_filmstrip`.
+    filmstrip: RefCell<Option<RgbaImage>>,
+    /// When to draw the information and filmstrip again for the view, see
+    /// `update_overlays`.
+    overlays_due: Cell<Option<Instant>>,
+    /// Images shown together instead of one, see `start_comparison`.
+    comparison: RefCell<Option<Comparison>>,
+    /// How the difference of images compared is shown.
+    diff_options: Cell<DiffOptions>,
+    /// Whether the file reader puts the images it reads on screen itself.
+    reader_shows: Arc<AtomicBool>,
+}
+
 impl Window {
     pub fn set_image(&self, image_pair: ImagePair) -> Result<()> {
-        let path = image_pair.0.clone();
         let name = image_pair.path_str().to_string();
+        let title = image_title(&image_pair);
         let image = image_pair.image().unwrap();
+        if self.comparing() {
+            return self.compare_with(title, image, false);
+        }
+        self.show(name, image)?;
+        self.reset_image();
+        self.update_grid()?;
+        self.set_title(title)
+    }
+
+    /// Tell that the image at `path` could not be shown, with a placeholder
+    /// in its place.
+    pub fn show_error(&self, path: &Path, error: &str) -> Result<()> {
+        let image = placeholder::error_image(path, error);
+        if self.comparing() {
+            return self.compare_with(format!("{}: {}", display_path(path), error), image, false);
+        }
+        self.show(display_path(path), image)?;
+
+        self.reset_image();
+        self.update_grid()?;
+        self.set_title(format!("{}: {}", display_path(path), error))
+    }
+
+    /// Keep `image_pair` in place of the image kept when comparing, e.g. the
+    /// full image once its preview was kept, or the first image of the next
+    /// pair.
+    pub fn set_kept_image(&self, image_pair: ImagePair) -> Result<()> {
+        let title = image_title(&image_pair);
+        self.compare_with(title, image_pair.image().unwrap(), true)
+    }
+
+    /// Like `show_error`, for the image kept when comparing.
+    pub fn show_kept_error(&self, path: &Path, error: &str) -> Result<()> {
+        let image = placeholder::error_image(path, error);
+        self.compare_with(format!("{}: {}", display_path(path), error), image, true)
+    }
+
+    /// Put `image` on screen as is, to be magnified later if needed.
+    fn show(&self, name: String, image: DynamicImage) -> Result<()> {
+        self.source.replace(Some(image.clone()));
+        let size = glam::UVec2::new(image.width(), image.height());
+        let window = self.inner_size().as_vec2();
+        let image = self.background.read().unwrap().apply(image, window);
         self.window
-            .set_image(name, image)
+            .set_image(name.clone(), image)
             .map_err(|_| anyhow!("Cannot apply the image"))?;
+        self.name.replace(name);
+        self.size.set(Some(size.as_vec2()));
+        self.texture.set(Texture::whole(size.as_vec2()));
+        Ok(())
+    }
 
-        let title = std::env::current_dir()
-            .ok()
-            .and_then(|cwd| path.strip_prefix(&cwd).ok().map(|p| p.to_owned()))
-            .unwrap_or(path)
-            .to_string_lossy()
-            .into_owned();
+    fn set_title(&self, title: String) -> Result<()> {
+        self.title.replace(title);
+        let title = self.full_title();
+        self.window
+            .run_function_wait(move |mut window_handle| {
+                window_handle.set_title(&title);
+            })
+            .map_err(|_| anyhow!("Cannot set window title"))
+    }
+
+    /// The title, with how the view alters the image.
+    fn full_title(&self) -> String {
+        let view = self.view.get();
+        let alterations: Vec<_> = view
+            .angle_description()
+            .into_iter()
+            .chain(view.flip_description().map(String::from))
+            .collect();
+        let title = self.title.borrow();
+        if alterations.is_empty() {
+            title.clone()
+        } else {
+            format!("{} [{}]", title, alterations.join(", "))
+        }
+    }
+
+    /// Show what is being typed for a command in the title bar, or go back to
+    /// the plain title.
+    pub fn show_prompt(&self, prompt: Option<&str>) -> Result<()> {
+        let title = match prompt {
+            Some(prompt) => format!("{} | {}", self.full_title(), prompt),
+            None => self.full_title(),
+        };
         self.window
             .run_function_wait(move |mut window_handle| {
                 window_handle.set_title(&title);
-                // Reset state that may have been changed by rotation
-                window_handle.set_preserve_aspect_ratio(true);
-                window_handle.set_transform(glam::Affine2::IDENTITY);
             })
-            .map_err(|_| anyhow!("Cannot set window title"))?;
+            .map_err(|_| anyhow!("Cannot set window title"))
+    }
+
+    /// Swap the displayed pixels for a different rendition of the same image
+    /// (e.g. a re-rasterized SVG), keeping title and what is shown where.
+    pub fn replace_image(&self, image_pair: ImagePair) -> Result<()> {
+        if self.comparing() {
+            return Ok(());
+        }
+        let name = image_pair.path_str().to_string();
+        let old_width = self.size.get().map(|size| size.x);
+        self.show(name, image_pair.image().unwrap())?;
 
-        self.reset_pan();
+        self.update_view(move |view, image, _| {
+            if let Some(old_width) = old_width {
+                view.zoom *= old_width / image.x;
+            }
+        });
         Ok(())
     }
 
+    pub fn inner_size(&self) -> glam::UVec2 {
+        self.window
+            .run_function_wait(|window_handle| window_handle.inner_size())
+            .expect("Failed to get window size")
+    }
+
+    /// Size of the image on screen, before any rotation.
+    pub fn displayed_size(&self) -> Option<glam::Vec2> {
+        let zoom = self.view.get().zoom;
+        self.image_size().map(|size| size.as_vec2() * zoom)
+    }
+
+    /// Size in pixels of the displayed image.
+    pub fn image_size(&self) -> Option<glam::UVec2> {
+        self.size.get().map(|size| size.as_uvec2())
+    }
+
     pub fn event_channel(
         &self,
     ) -> Result<
@@ -83,194 +251,697 @@ impl Window {
         self.window.event_channel()
     }
 
-    pub fn reset_pan(&self) {
-        self.window
-            .run_function_wait(|mut window_handle| {
-                let scale = window_handle.transform().x_axis.length();
-                // Keep scale, position at top-left: x centered, y at top
-                let tx = (1.0 - scale) / 2.0;
-                let ty = 0.0;
-                let transform = glam::Affine2::from_scale_angle_translation(
-                    glam::Vec2::splat(scale),
-                    0.0,
-                    glam::Vec2::new(tx, ty),
-                );
-                window_handle.set_transform(transform);
+    /// Change the view, then apply it to the window. Nothing happens while
+    /// there is no image or the window has no area.
+    fn update_view(
+        &self,
+        change: impl FnOnce(&mut ViewState, glam::Vec2, glam::Vec2) + Send + 'static,
+    ) {
+        let Some(image) = self.size.get() else {
+            return;
+        };
+        let mut view = self.view.get();
+        let placement = self.texture.get().placement(image);
+        let comparing = self.comparing();
+        let mode = self.canvas_mode();
+        let view = self
+            .window
+            .run_function_wait(move |mut window_handle| {
+                let window = window_handle.inner_size().as_vec2();
+                if window.min_element() < 1.0 {
+                    return view;
+                }
+
+                match mode {
+                    // Panes all look alike, the first stands for them
+                    Some(mode) => change(&mut view, image, mode.panes(window)[0].1),
+                    None => {
+                        change(&mut view, image, window);
+                        window_handle.set_preserve_aspect_ratio(false);
+                        window_handle.set_transform(view.transform(image, window) * placement);
+                    }
+                }
+                view
             })
-            .expect("Failed to reset pan");
+            .expect("Failed to update the view");
+        self.view.set(view);
+        if comparing {
+            self.update_comparison();
+        }
+        if mode.is_some() {
+            return;
+        }
+        self.update_magnification();
+        // Moving along with the image meanwhile
+        self.overlays_due.set(Some(Instant::now() + OVERLAY_DELAY));
     }
 
-    pub fn reset_image(&self) {
+    /// When `update_overlays` is to be called, if the view has changed.
+    pub fn overlays_due(&self) -> Option<Instant> {
+        self.overlays_due.get()
+    }
+
+    /// Draw the information and filmstrip again where they belong for the
+    /// view, once it has stopped changing.
+    pub fn update_overlays(&self) -> Result<()> {
+        if self
+            .overlays_due
+            .get()
+            .is_some_and(|due| due <= Instant::now())
+        {
+            self.overlays_due.set(None);
+            if self.hud.get() {
+                self.update_hud()?;
+            }
+            self.update_filmstrip()?;
+        }
+        Ok(())
+    }
+
+    /// Tell about the image shown on screen, with the zoom and orientation.
+    pub fn set_info(&self, lines: Vec<String>) -> Result<()> {
+        self.info.replace(lines);
+        if self.hud.get() {
+            self.update_hud()?;
+        }
+        Ok(())
+    }
+
+    /// Coordinates and value of the image pixel under `position` (window
+    /// pixels), if there is one.
+    pub fn inspect(&self, position: glam::Vec2) -> Option<String> {
+        if self.comparing() {
+            return None;
+        }
+        let image = self.size.get()?;
+        let window = self.inner_size().as_vec2();
+        let point = self.view.get().image_point(position, image, window) * image;
+        if point.cmplt(glam::Vec2::ZERO).any() {
+            return None;
+        }
+        let source = self.source.borrow();
+        inspect::describe_pixel(source.as_ref()?, point.x as u32, point.y as u32)
+    }
+
+    /// Show what the pixel inspector found in the title and the on-screen
+    /// information, or stop showing it.
+    pub fn show_inspection(&self, inspection: Option<String>) -> Result<()> {
+        self.show_prompt(inspection.as_deref())?;
+        self.inspection.replace(inspection);
+        if self.hud.get() {
+            self.update_hud()?;
+        }
+        Ok(())
+    }
+
+    /// Show or hide the on-screen information.
+    pub fn toggle_hud(&self) -> Result<()> {
+        self.hud.set(!self.hud.get());
+        if self.hud.get() {
+            return self.update_hud();
+        }
         self.window
             .run_function_wait(|mut window_handle| {
-                let scale = 1f32;
-
-                let transform = glam::Affine2::from_scale_angle_translation(
-                    glam::Vec2::splat(scale),
-                    0.0,
-                    glam::Vec2::new(0.0, 0.0),
-                );
+                window_handle.remove_overlay(&HUD_OVERLAY);
+            })
+            .map_err(|_| anyhow!("Cannot hide the information"))
+    }
 
-                window_handle.set_transform(transform);
+    fn update_hud(&self) -> Result<()> {
+        let Some(image) = self.size.get() else {
+            return Ok(());
+        };
+        let view = self.view.get();
+        let mut lines = self.info.borrow().clone();
+        lines.push(hud::describe_view(&view));
+        lines.extend(self.inspection.borrow().clone());
+        self.window
+            .run_function_wait(move |mut window_handle| {
+                let window = window_handle.inner_size().as_vec2();
+                if window.min_element() < 1.0 {
+                    return;
+                }
+                let overlay = DynamicImage::ImageRgba8(hud::overlay(&lines, &view, image, window));
+                if let Ok(overlay) = overlay.as_image_view() {
+                    window_handle.set_overlay(HUD_OVERLAY, &overlay, true);
+                }
             })
-            .expect("XXX TODO reset_scale failed");
+            .map_err(|_| anyhow!("Cannot draw the information"))
     }
 
-    pub fn scale_down(&self) {
+    /// Show `strip` (see `Filmstrip`) along the bottom of the window, or
+    /// stop showing it.
+    pub fn show_filmstrip(&self, strip: Option<RgbaImage>) -> Result<()> {
+        let shown = strip.is_some();
+        self.filmstrip.replace(strip);
+        if shown {
+            return self.update_filmstrip();
+        }
         self.window
             .run_function_wait(|mut window_handle| {
-                let transform = window_handle.transform();
-                let scale_x = transform.x_axis.length();
-
-                // TODO should use a curve, maybe exp, to smooth it out
-
-                // never reach 0
-                let scale = if scale_x > 1.0 {
-                    scale_x / 1.25
-                } else if scale_x > 0.2 {
-                    scale_x - 0.1
-                } else {
-                    scale_x
-                };
-
-                let origin = glam::Vec2::splat((1.0 - scale) / 2.0);
-
-                let transform = glam::Affine2::from_scale_angle_translation(
-                    glam::Vec2::splat(scale),
-                    0.0,
-                    origin,
-                );
+                window_handle.remove_overlay(&FILMSTRIP_OVERLAY);
+            })
+            .map_err(|_| anyhow!("Cannot hide the filmstrip"))
+    }
 
-                window_handle.set_transform(transform);
+    fn update_filmstrip(&self) -> Result<()> {
+        let Some(image) = self.size.get() else {
+            return Ok(());
+        };
+        let Some(strip) = self.filmstrip.borrow().clone() else {
+            return Ok(());
+        };
+        let view = self.view.get();
+        self.window
+            .run_function_wait(move |mut window_handle| {
+                let window = window_handle.inner_size().as_vec2();
+                if window.min_element() < 1.0 {
+                    return;
+                }
+                let size = glam::Vec2::new(strip.width() as f32, strip.height() as f32);
+                let origin = filmstrip::origin(size, hud::covered(&view, image, window));
+                let overlay = hud::place(&strip, origin, &view, image, window);
+                if let Ok(overlay) = DynamicImage::ImageRgba8(overlay).as_image_view() {
+                    window_handle.set_overlay(FILMSTRIP_OVERLAY, &overlay, true);
+                }
             })
-            .expect("XXX TODO reset_scale failed");
+            .map_err(|_| anyhow!("Cannot draw the filmstrip"))
     }
 
-    pub fn scale_up(&self) {
+    /// Where `position` (window pixels) is on the filmstrip, if it is shown
+    /// there.
+    pub fn filmstrip_point(&self, position: glam::Vec2) -> Option<glam::Vec2> {
+        let image = self.size.get()?;
+        let size = {
+            let strip = self.filmstrip.borrow();
+            let strip = strip.as_ref()?;
+            glam::Vec2::new(strip.width() as f32, strip.height() as f32)
+        };
+        let window = self.inner_size().as_vec2();
+        let (min, max) = hud::covered(&self.view.get(), image, window);
+        let point = position - filmstrip::origin(size, (min, max));
+        let visible = position.cmpge(min).all() && position.cmplt(max).all();
+        (visible && point.cmpge(glam::Vec2::ZERO).all() && point.cmplt(size).all()).then_some(point)
+    }
+
+    /// Whether two images are shown, see `start_comparison`.
+    pub fn comparing(&self) -> bool {
+        self.comparison.borrow().is_some()
+    }
+
+    /// Keep the image shown on screen, to show the next ones beside it with
+    /// the same view.
+    pub fn start_comparison(&self) {
+        let Some(source) = self.source.borrow().clone() else {
+            return;
+        };
+        let pane = CompareMode::SideBySide.panes(self.inner_size().as_vec2())[0].1;
+        let mut comparison = Comparison::new(
+            self.title.borrow().clone(),
+            source,
+            &self.background.read().unwrap(),
+            pane,
+        );
+        comparison.set_diff_options(self.diff_options.get());
+        let title = comparison.title();
+        self.comparison.replace(Some(comparison));
+        self.reader_shows.store(false, Ordering::Relaxed);
         self.window
             .run_function_wait(|mut window_handle| {
-                let transform = window_handle.transform();
-                let scale_x = transform.x_axis.length();
-
-                let scale = if scale_x < 1.0 {
-                    scale_x + 0.1
-                } else if scale_x < 4.0 {
-                    scale_x * 1.25
-                } else {
-                    scale_x
-                };
-
-                let origin = glam::Vec2::splat((1.0 - scale) / 2.0);
-
-                let transform = glam::Affine2::from_scale_angle_translation(
-                    glam::Vec2::splat(scale),
-                    0.0,
-                    origin,
-                );
+                window_handle.set_overlays_visible(false);
+            })
+            .expect("Failed to hide the overlays");
+        self.refresh_view();
+        let _ = self.set_title(title);
+    }
 
-                window_handle.set_transform(transform);
+    /// Back to one image, the one shown next to the kept one, with the same
+    /// view.
+    pub fn stop_comparison(&self) -> Result<()> {
+        let Some(comparison) = self.comparison.take() else {
+            return Ok(());
+        };
+        self.reader_shows.store(true, Ordering::Relaxed);
+        self.window
+            .run_function_wait(|mut window_handle| {
+                window_handle.set_overlays_visible(true);
             })
-            .expect("XXX TODO reset_scale failed");
+            .map_err(|_| anyhow!("Cannot show the overlays"))?;
+
+        let old_width = comparison.size().x;
+        let (title, image) = comparison.into_last();
+        self.show(title.clone(), image)?;
+        self.update_view(move |view, image, window| {
+            view.zoom *= old_width / image.x;
+            view.clamp_pan(image, window);
+        });
+        self.update_grid()?;
+        self.set_title(title)
+    }
+
+    /// Put the images together the next way. Returns how, if comparing.
+    pub fn next_compare_mode(&self) -> Option<CompareMode> {
+        let mode = {
+            let mut comparison = self.comparison.borrow_mut();
+            let comparison = comparison.as_mut()?;
+            comparison.mode = comparison.mode.next();
+            comparison.mode
+        };
+        self.refresh_view();
+        self.update_comparison_title();
+        Some(mode)
     }
-    pub fn rotate(&self, direction: Rotation) {
+
+    /// Change how the difference of images compared is shown. Returns the
+    /// new options.
+    pub fn update_diff_options(&self, change: impl FnOnce(&mut DiffOptions)) -> DiffOptions {
+        let mut options = self.diff_options.get();
+        change(&mut options);
+        self.diff_options.set(options);
+        if let Some(comparison) = self.comparison.borrow_mut().as_mut() {
+            comparison.set_diff_options(options);
+        }
+        self.update_comparison();
+        self.update_comparison_title();
+        options
+    }
+
+    /// Tell about the images compared in the title.
+    fn update_comparison_title(&self) {
+        let title = self.comparison.borrow().as_ref().map(Comparison::title);
+        if let Some(title) = title {
+            let _ = self.set_title(title);
+        }
+    }
+
+    /// Swap the sides of the images compared, or which one shows when
+    /// blinking.
+    pub fn swap_comparison(&self) {
+        if let Some(comparison) = self.comparison.borrow_mut().as_mut() {
+            comparison.swapped = !comparison.swapped;
+        }
+        self.update_comparison();
+        self.update_comparison_title();
+    }
+
+    /// Whether the images compared take turns on screen, see
+    /// `swap_comparison`.
+    pub fn blinking(&self) -> bool {
+        self.comparison
+            .borrow()
+            .as_ref()
+            .is_some_and(|comparison| comparison.mode == CompareMode::Blink)
+    }
+
+    /// Where the line between the images compared is (window pixels from
+    /// the left), when wiping.
+    pub fn wipe_line(&self) -> Option<f32> {
+        let comparison = self.comparison.borrow();
+        let comparison = comparison.as_ref()?;
+        (comparison.mode == CompareMode::Wipe)
+            .then(|| comparison.wipe_line(self.inner_size().x as f32))
+    }
+
+    /// Move the line between the images compared to `x` (window pixels).
+    pub fn set_wipe(&self, x: f32) {
+        let width = self.inner_size().x as f32;
+        if let Some(comparison) = self.comparison.borrow_mut().as_mut() {
+            comparison.wipe = (x / width).clamp(0.0, 1.0);
+        }
+        self.update_comparison();
+    }
+
+    /// Show `image`, with `title`, next to the one kept, or keep it instead.
+    fn compare_with(&self, title: String, image: DynamicImage, kept: bool) -> Result<()> {
+        if let Some(comparison) = self.comparison.borrow_mut().as_mut() {
+            let pane = comparison.mode.panes(self.inner_size().as_vec2())[0].1;
+            let background = self.background.read().unwrap();
+            if kept {
+                comparison.set_pinned(title, image, &background, pane);
+                // The view is about the kept image, the same part of which stays shown
+                let size = comparison.size();
+                if let Some(old) = self.size.replace(Some(size)) {
+                    let mut view = self.view.get();
+                    view.zoom *= old.x / size.x;
+                    self.view.set(view);
+                }
+            } else {
+                comparison.set_other(title, image, &background, pane);
+            }
+        }
+        self.update_comparison();
+        self.update_comparison_title();
+        Ok(())
+    }
+
+    /// How the images compared are put together, when they are drawn on a
+    /// canvas rather than shown one at a time and moved by the view.
+    fn canvas_mode(&self) -> Option<CompareMode> {
+        self.comparison
+            .borrow()
+            .as_ref()
+            .filter(|comparison| !comparison.single())
+            .map(|comparison| comparison.mode)
+    }
+
+    /// Put the images compared on screen as the view shows them, unless
+    /// they already are: the one image shown like any other, or all drawn
+    /// on a canvas.
+    fn update_comparison(&self) {
+        let window = self.inner_size();
+        if window.min_element() == 0 {
+            return;
+        }
+        let frame = {
+            let mut comparison = self.comparison.borrow_mut();
+            let Some(comparison) = comparison.as_mut() else {
+                return;
+            };
+            comparison.update_difference();
+            let color = self.background.read().unwrap().color();
+            comparison.frame(&self.view.get(), window, color, !self.nearest())
+        };
+        let canvas = match frame {
+            Frame::Unchanged => return,
+            Frame::Image(name, image) => {
+                if self.show(name, image).is_ok() {
+                    self.refresh_view();
+                }
+                return;
+            }
+            Frame::Canvas(canvas) => canvas,
+        };
+        if self
+            .window
+            .set_image("comparison", DynamicImage::ImageRgba8(canvas))
+            .is_err()
+        {
+            return;
+        }
         self.window
-            .run_function_wait(move |mut window_handle| {
+            .run_function_wait(|mut window_handle| {
                 window_handle.set_preserve_aspect_ratio(false);
-                let cur_transform = window_handle.transform();
-
-                // x-axis y-axis (assuming we rotate clockwise)
-                //  1/ 0  0/ 1   # ⮝
-                //  0/ 1 -1/ 0   # ⮞
-                // -1/ 0  0/-1   # ⮟
-                //  0/-1  1/ 0   # ⮜
-                // As usual with floats, they're hard to compare for equality
-
-                // (if I could remember more of algebra, I wouldn't need to detect the current rotation... )
-                let r_state = match cur_transform.matrix2.to_cols_array() {
-                    k if k[0] > k[1] && k[2] < k[3] => RotationState::UP,
-                    k if k[0] < k[1] && k[2] < k[3] => RotationState::RIGHT,
-                    k if k[0] < k[1] && k[2] > k[3] => RotationState::DOWN,
-                    k if k[0] > k[1] && k[2] > k[3] => RotationState::LEFT,
-                    _ => RotationState::UP,
-                };
-
-                let r_state = r_state.rotate(direction);
-
-                let angle = std::f32::consts::PI / 2.0
-                    * match r_state {
-                        RotationState::UP => 0.0,
-                        RotationState::RIGHT => 1.0,
-                        RotationState::DOWN => 2.0,
-                        RotationState::LEFT => 3.0,
-                    };
-
-                let rotate = glam::Affine2::from_angle(angle);
-
-                let image_size = window_handle.image_info().unwrap().size.as_vec2();
-                let mut inner_size = window_handle.inner_size().as_vec2();
-
-                // is it going to be rotated 90 or 180 degree? Invert x with y
-                if r_state == RotationState::RIGHT || r_state == RotationState::LEFT {
-                    inner_size = glam::Vec2::new(inner_size.y, inner_size.x);
-                }
+                window_handle.set_transform(glam::Affine2::IDENTITY);
+            })
+            .expect("Failed to show the comparison");
+    }
+
+    /// Size of the part of the window an image is shown in: a pane of it
+    /// when comparing images.
+    pub fn viewport_size(&self) -> glam::Vec2 {
+        let window = self.inner_size().as_vec2();
+        match self.comparison.borrow().as_ref() {
+            Some(comparison) => comparison.mode.panes(window)[0].1,
+            None => window,
+        }
+    }
 
-                let (fit_transform, _) = fit(inner_size, image_size);
+    /// `position` (window pixels) relative to the pane it is in, when
+    /// comparing images.
+    fn viewport_point(&self, position: glam::Vec2) -> glam::Vec2 {
+        let window = self.inner_size().as_vec2();
+        let Some(mode) = self.comparison.borrow().as_ref().map(|c| c.mode) else {
+            return position;
+        };
+        let origin = mode
+            .panes(window)
+            .into_iter()
+            .rev()
+            .find(|(origin, _)| position.cmpge(*origin).all())
+            .map_or(glam::Vec2::ZERO, |(origin, _)| origin);
+        position - origin
+    }
 
-                let position =
-                    glam::Affine2::from_translation(glam::Vec2::from_slice(match r_state {
-                        RotationState::UP => &[0.0, 0.0],
-                        RotationState::RIGHT => &[1.0, 0.0],
-                        RotationState::DOWN => &[1.0, 1.0],
-                        RotationState::LEFT => &[0.0, 1.0],
-                    }));
+    /// Whether the file reader puts images on screen, to share with it. It
+    /// doesn't while the window shows a comparison.
+    pub fn reader_shows(&self) -> Arc<AtomicBool> {
+        self.reader_shows.clone()
+    }
 
-                window_handle.set_transform(position * rotate * fit_transform);
+    /// What is behind transparent parts of images, to share with whatever
+    /// else puts images in the window.
+    pub fn background(&self) -> Arc<RwLock<Background>> {
+        self.background.clone()
+    }
+
+    /// Show images over `background` from now on, the current one included.
+    pub fn set_background(&self, background: Background) -> Result<()> {
+        let previous = std::mem::replace(&mut *self.background.write().unwrap(), background);
+        let [r, g, b, _] = background.color().0.map(|c| c as f64 / 255.0);
+        self.window
+            .run_function_wait(move |mut window_handle| {
+                window_handle.set_background_color(show_image::Color::rgb(r, g, b));
             })
-            .expect("XXX TODO rotate failed");
+            .map_err(|_| anyhow!("Cannot set the background color"))?;
+
+        if let Some(comparison) = self.comparison.borrow_mut().as_mut() {
+            let pane = comparison.mode.panes(self.inner_size().as_vec2())[0].1;
+            comparison.set_background(&background, pane);
+        }
+        if self.comparing() {
+            self.update_comparison();
+            return Ok(());
+        }
+        // Solid colors are the window's own, only checkerboards are drawn
+        let checkerboard = BackgroundMode::Checkerboard;
+        if previous.mode != checkerboard && background.mode != checkerboard {
+            return Ok(());
+        }
+        let transparent = self.source.borrow().clone();
+        if let Some(image) = transparent.filter(|image| image.color().has_alpha()) {
+            self.show(self.name.borrow().clone(), image)?;
+            self.refresh_view();
+        }
+        Ok(())
     }
 
-    pub fn pan(&self, dx: f32, dy: f32) {
+    /// Show sharp pixels from `percent` zoom, or never if None.
+    pub fn set_nearest_above(&self, percent: Option<f32>) {
+        self.nearest_above
+            .set(percent.map(|percent| percent / 100.0));
+        self.update_magnification();
+    }
+
+    /// Switch between sharp pixels at any zoom and from the configured one.
+    /// Returns whether they are now sharp at any zoom.
+    pub fn toggle_nearest(&self) -> bool {
+        self.nearest_forced.set(!self.nearest_forced.get());
+        self.update_magnification();
+        self.nearest_forced.get()
+    }
+
+    /// Whether pixels are to be shown as sharp squares at the current zoom.
+    fn nearest(&self) -> bool {
+        let zoom = self.view.get().zoom;
+        (self.nearest_forced.get() && zoom > 1.0)
+            || self.nearest_above.get().is_some_and(|above| zoom >= above)
+    }
+
+    /// Upload the part of the image in view magnified (or the whole image
+    /// as is) and outline its pixels (or not), depending on the zoom.
+    fn update_magnification(&self) {
+        if self.canvas_mode().is_some() {
+            // Sampled as needed when drawn
+            self.update_comparison();
+            return;
+        }
+        let Some(size) = self.size.get() else {
+            return;
+        };
+        let view = self.view.get();
+        let window = self.inner_size().as_vec2();
+        let visible = view.visible_part(size, window);
+        let texels = texels_per_pixel(visible.1 - visible.0, view.zoom);
+        // Any coarser, lines would be as thick as the pixels
+        let outline = view.zoom >= PIXEL_GRID_ZOOM && texels >= 4;
+        let scale = if self.nearest() || outline { texels } else { 1 };
+
+        let current = self.texture.get();
+        let texture = if scale == 1 {
+            Texture::whole(size)
+        } else if current.scale == scale && current.outline == outline && current.contains(visible)
+        {
+            current
+        } else {
+            let (min, max) = magnified_part(visible, size, texels);
+            Texture {
+                min: min.as_uvec2(),
+                max: max.as_uvec2(),
+                scale,
+                outline,
+            }
+        };
+
+        if texture != current {
+            let Some(source) = self.source.borrow().clone() else {
+                return;
+            };
+            let part = texture.max - texture.min;
+            // Each pixel a square of texels, which smoothing can't blur
+            let magnified = source
+                .crop_imm(texture.min.x, texture.min.y, part.x, part.y)
+                .resize_exact(
+                    part.x * texture.scale,
+                    part.y * texture.scale,
+                    FilterType::Nearest,
+                );
+            let magnified = self.background.read().unwrap().apply_part(
+                magnified,
+                size * texture.scale as f32,
+                texture.min * texture.scale,
+                window,
+            );
+            let magnified = if texture.outline {
+                outline_pixels(magnified, texture.scale)
+            } else {
+                magnified
+            };
+            if self.window.set_image("magnified", magnified).is_err() {
+                return;
+            }
+            self.texture.set(texture);
+            let placement = texture.placement(size);
+            self.window
+                .run_function_wait(move |mut window_handle| {
+                    let window = window_handle.inner_size().as_vec2();
+                    window_handle.set_transform(view.transform(size, window) * placement);
+                })
+                .expect("Failed to update the view");
+        }
+    }
+
+    /// Compute the transform again, e.g. because the window was resized.
+    pub fn refresh_view(&self) {
+        self.update_view(|view, image, window| view.clamp_pan(image, window));
+    }
+
+    /// Back to the whole image, unrotated.
+    pub fn reset_image(&self) {
+        self.update_view(|view, image, window| {
+            *view = ViewState::default();
+            view.fit(FitMode::Fit, image, window);
+        });
+    }
+
+    pub fn scale_down(&self) {
+        self.update_view(|view, image, window| view.step_zoom(false, image, window));
+    }
+
+    pub fn scale_up(&self) {
+        self.update_view(|view, image, window| view.step_zoom(true, image, window));
+    }
+
+    /// Zoom by `factor`, keeping still the point under `anchor` (in window
+    /// pixels, e.g. the mouse cursor) or else the center of the viewport.
+    pub fn zoom_by(&self, factor: f32, anchor: Option<glam::Vec2>) {
+        let anchor = anchor.map(|anchor| self.viewport_point(anchor));
+        self.update_view(move |view, image, window| {
+            view.zoom_to(view.zoom * factor, anchor, image, window)
+        });
+    }
+
+    /// Zoom to `percent` of the image pixels, around the viewport center.
+    pub fn zoom_to(&self, percent: f32) {
+        self.update_view(move |view, image, window| {
+            view.zoom_to(percent / 100.0, None, image, window)
+        });
+    }
+
+    /// What is shown and how.
+    pub fn view(&self) -> ViewState {
+        self.view.get()
+    }
+
+    /// Show the image as `view` does, e.g. as it was seen earlier.
+    pub fn restore_view(&self, view: ViewState) -> Result<()> {
+        self.update_view(move |current, image, window| {
+            *current = view;
+            current.clamp_pan(image, window);
+        });
+        self.update_grid()?;
+        self.show_prompt(None)
+    }
+
+    /// Current zoom, in percent of the image pixels.
+    pub fn zoom_percent(&self) -> f32 {
+        self.view.get().zoom * 100.0
+    }
+
+    pub fn rotate(&self, direction: Rotation) -> Result<()> {
+        self.update_view(move |view, image, window| view.rotate(direction, image, window));
+        self.update_grid()
+    }
+
+    /// Tilt the image by `degrees` clockwise.
+    pub fn rotate_by(&self, degrees: f32) -> Result<()> {
+        self.update_view(move |view, image, window| view.rotate_by(degrees, image, window));
+        self.update_grid()?;
+        self.show_prompt(None)
+    }
+
+    /// Rotate the image to exactly `degrees` clockwise.
+    pub fn set_rotation(&self, degrees: f32) -> Result<()> {
+        self.update_view(move |view, image, window| view.set_rotation(degrees, image, window));
+        self.update_grid()?;
+        self.show_prompt(None)
+    }
+
+    /// Mirror the image as seen on screen.
+    pub fn flip(&self, flip: Flip) -> Result<()> {
+        self.update_view(move |view, image, window| view.flip(flip, image, window));
+        self.update_grid()?;
+        self.show_prompt(None)
+    }
+
+    /// Show or hide lines to straighten the image against.
+    pub fn toggle_grid(&self) -> Result<()> {
+        self.grid.set(!self.grid.get());
+        self.update_grid()
+    }
+
+    /// Draw the grid again for the current image and orientation, or remove
+    /// it if hidden.
+    fn update_grid(&self) -> Result<()> {
+        let view = self.view.get();
+        let visible = self.grid.get();
+        let image = self.size.get();
         self.window
             .run_function_wait(move |mut window_handle| {
-                let eff = window_handle.effective_transform();
-                let (min_x, max_x, min_y, max_y) = image_bounds(&eff);
-
-                let extent_x = max_x - min_x;
-                let extent_y = max_y - min_y;
-                let eps = 0.001;
-
-                // Only allow panning on axes where the image extends beyond
-                // the viewport, and only in the direction where there's room.
-                let actual_dx = if extent_x > 1.0 + eps {
-                    if dx > 0.0 && min_x < -eps {
-                        dx
-                    } else if dx < 0.0 && max_x > 1.0 + eps {
-                        dx
-                    } else {
-                        0.0
+                let window = window_handle.inner_size().as_vec2();
+                match image {
+                    Some(image) if visible && window.min_element() >= 1.0 => {
+                        // About as many pixels as the image fitted in the window
+                        let size = (image * window.max_element() / image.max_element())
+                            .round()
+                            .max(glam::Vec2::ONE);
+                        let grid = DynamicImage::ImageRgba8(straighten::grid_overlay(
+                            &view,
+                            image,
+                            (size.x as u32, size.y as u32),
+                        ));
+                        if let Ok(grid) = grid.as_image_view() {
+                            window_handle.set_overlay(GRID_OVERLAY, &grid, true);
+                        }
                     }
-                } else {
-                    0.0
-                };
-                let actual_dy = if extent_y > 1.0 + eps {
-                    if dy > 0.0 && min_y < -eps {
-                        dy
-                    } else if dy < 0.0 && max_y > 1.0 + eps {
-                        dy
-                    } else {
-                        0.0
+                    _ => {
+                        window_handle.remove_overlay(&GRID_OVERLAY);
                     }
-                } else {
-                    0.0
-                };
-
-                if actual_dx != 0.0 || actual_dy != 0.0 {
-                    let shift =
-                        glam::Affine2::from_translation(glam::Vec2::new(actual_dx, actual_dy));
-                    window_handle.pre_apply_transform(shift);
                 }
             })
-            .expect("Failed to pan");
+            .map_err(|_| anyhow!("Cannot draw the grid"))
+    }
+
+    /// Size the image according to `mode`, keeping its rotation. The image is
+    /// centered.
+    pub fn apply_fit(&self, mode: FitMode) {
+        self.update_view(move |view, image, window| view.fit(mode, image, window));
+    }
+
+    /// Move the image by a fraction of the viewport size.
+    pub fn pan(&self, dx: f32, dy: f32) {
+        self.update_view(move |view, image, window| {
+            view.pan(glam::Vec2::new(dx, dy) * window, image, window)
+        });
     }
 
     pub fn toggle_fullscreen(&self) {
@@ -285,56 +956,153 @@ impl Window {
     pub fn exit(&self) {}
 }
 
+/// Texels per image pixel to show `size` image pixels at `zoom` with sharp
+/// pixels, within the texture size limit.
+fn texels_per_pixel(size: glam::Vec2, zoom: f32) -> u32 {
+    let fits = MAX_MAGNIFIED_SIDE / (size.max_element().ceil() as u32).max(1);
+    (zoom.floor() as u32).min(fits).max(1)
+}
+
+/// Draw lines between the pixels of `image`, each a square of `scale` texels.
+fn outline_pixels(image: DynamicImage, scale: u32) -> DynamicImage {
+    let mut image = image.into_rgba8();
+    let (width, height) = image.dimensions();
+    for y in 0..height {
+        // The whole row on lines, the columns on others
+        let step = if y % scale == 0 { 1 } else { scale as usize };
+        for x in (0..width).step_by(step) {
+            let pixel = image.get_pixel_mut(x, y);
+            let line = PIXEL_GRID_COLOR[3] as u32;
+            let under = pixel[3] as u32 * (255 - line) / 255;
+            for i in 0..3 {
+                pixel[i] = ((PIXEL_GRID_COLOR[i] as u32 * line + pixel[i] as u32 * under)
+                    / (line + under)) as u8;
+            }
+            pixel[3] = (line + under) as u8;
+        }
+    }
+    DynamicImage::ImageRgba8(image)
+}
+
+/// Corners (whole image pixels) of the part of an image of `size` to
+/// magnify by `texels` while `visible` is seen: that, with a margin for pans
+/// not to need another, within the texture size limit.
+fn magnified_part(
+    (min, max): (glam::Vec2, glam::Vec2),
+    size: glam::Vec2,
+    texels: u32,
+) -> (glam::Vec2, glam::Vec2) {
+    let visible = max - min;
+    // Rounding outwards takes up to a pixel on each side
+    let room = glam::Vec2::splat((MAX_MAGNIFIED_SIDE / texels) as f32 - 2.0);
+    let margin = ((room - visible) / 2.0)
+        .min(visible / 2.0)
+        .max(glam::Vec2::ZERO);
+    (
+        (min - margin).floor().max(glam::Vec2::ZERO),
+        (max + margin).ceil().min(size),
+    )
+}
+
+/// Path as shown to the user: relative to the current directory when possible.
+fn display_path(path: &Path) -> String {
+    std::env::current_dir()
+        .ok()
+        .and_then(|cwd| path.strip_prefix(&cwd).ok().map(|p| p.to_owned()))
+        .unwrap_or_else(|| path.to_owned())
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Title of an image: its path, with the page and sensor size if any.
+fn image_title(image_pair: &ImagePair) -> String {
+    let details = &image_pair.2;
+    let mut title = display_path(&image_pair.0);
+    if details.subimages > 1 {
+        title.push_str(&format!(
+            " (page {}/{})",
+            details.subimage + 1,
+            details.subimages
+        ));
+    }
+    if let Some((w, h)) = details.sensor_size {
+        title.push_str(&format!(" (sensor {}x{})", w, h));
+    }
+    title
+}
+
 pub fn generate_window() -> Result<Window> {
     let window = show_image::create_window(
         "image",
         show_image::WindowOptions {
-            preserve_aspect_ratio: true,
+            preserve_aspect_ratio: false,
+            // Zoom and pan are ours, see minimg's event loop
+            default_controls: false,
             ..Default::default()
         },
     )?;
 
-    Ok(Window { window })
+    Ok(Window {
+        window,
+        title: RefCell::new(String::new()),
+        name: RefCell::new(String::new()),
+        view: Cell::new(ViewState::default()),
+        grid: Cell::new(false),
+        size: Cell::new(None),
+        source: RefCell::new(None),
+        texture: Cell::new(Texture::whole(glam::Vec2::ZERO)),
+        nearest_above: Cell::new(None),
+        nearest_forced: Cell::new(false),
+        background: Arc::new(RwLock::new(Background::default())),
+        info: RefCell::new(Vec::new()),
+        hud: Cell::new(false),
+        inspection: RefCell::new(None),
+        filmstrip: RefCell::new(None),
+        overlays_due: Cell::new(None),
+        comparison: RefCell::new(None),
+        diff_options: Cell::new(DiffOptions::default()),
+        reader_shows: Arc::new(AtomicBool::new(true)),
+    })
 }
 
-fn fit(window_size: glam::Vec2, image_size: glam::Vec2) -> (glam::Affine2, glam::Vec2) {
-    let ratios = image_size / window_size;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::Vec2;
 
-    let w;
-    let h;
-    if ratios.x >= ratios.y {
-        w = 1.0;
-        h = ratios.y / ratios.x;
-    } else {
-        w = ratios.x / ratios.y;
-        h = 1.0;
-    }
-
-    let transform = glam::Affine2::from_scale_angle_translation(
-        glam::Vec2::new(w, h),
-        0.0,
-        0.5 * glam::Vec2::new(1.0 - w, 1.0 - h),
-    );
+    #[test]
+    fn test_magnified_part() {
+        let image = Vec2::new(10_000.0, 8_000.0);
+        // Magnified by 8 from a 5000px wide image, only what is in view
+        let visible = (Vec2::new(4000.0, 3000.0), Vec2::new(4240.0, 3135.0));
+        let texels = texels_per_pixel(visible.1 - visible.0, 8.5);
+        assert_eq!(8, texels);
+        let (min, max) = magnified_part(visible, image, texels);
+        assert_eq!(
+            (Vec2::new(3880.0, 2932.0), Vec2::new(4360.0, 3203.0)),
+            (min.floor(), max.ceil())
+        );
+        assert!(((max - min) * texels as f32).max_element() <= MAX_MAGNIFIED_SIDE as f32);
 
-    (transform, image_size)
-}
+        // Not past the image, nor the texture size limit
+        let visible = (Vec2::new(0.0, 0.0), Vec2::new(1000.0, 600.0));
+        let texels = texels_per_pixel(visible.1 - visible.0, 5.0);
+        assert_eq!(4, texels);
+        let (min, max) = magnified_part(visible, image, texels);
+        assert_eq!(Vec2::ZERO, min);
+        assert!(((max - min) * texels as f32).max_element() <= MAX_MAGNIFIED_SIDE as f32);
+        assert!(max.cmpge(visible.1).all());
+    }
 
-fn image_bounds(eff: &glam::Affine2) -> (f32, f32, f32, f32) {
-    let corners = [
-        eff.transform_point2(glam::Vec2::new(0.0, 0.0)),
-        eff.transform_point2(glam::Vec2::new(1.0, 0.0)),
-        eff.transform_point2(glam::Vec2::new(0.0, 1.0)),
-        eff.transform_point2(glam::Vec2::new(1.0, 1.0)),
-    ];
-    let min_x = corners.iter().map(|p| p.x).fold(f32::INFINITY, f32::min);
-    let max_x = corners
-        .iter()
-        .map(|p| p.x)
-        .fold(f32::NEG_INFINITY, f32::max);
-    let min_y = corners.iter().map(|p| p.y).fold(f32::INFINITY, f32::min);
-    let max_y = corners
-        .iter()
-        .map(|p| p.y)
-        .fold(f32::NEG_INFINITY, f32::max);
-    (min_x, max_x, min_y, max_y)
+    #[test]
+    fn test_outline_pixels() {
+        let white = Rgba([255, 255, 255, 255]);
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(8, 8, white));
+        let outlined = outline_pixels(image, 4).into_rgba8();
+        for (x, y, pixel) in outlined.enumerate_pixels() {
+            let line = x % 4 == 0 || y % 4 == 0;
+            assert_eq!(line, *pixel != white, "{} {}", x, y);
+            assert_eq!(255, pixel[3]);
+        }
+    }
 }